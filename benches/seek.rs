@@ -1,13 +1,11 @@
 #![feature(test)]
-#![feature(array_windows)]
-#![feature(array_chunks)]
 
 extern crate test;
-use test::bench::{Bencher, black_box};
+use test::bench::Bencher;
 
 use std::io;
 
-use breccia::{BrecciaMut, Search};
+use breccia::BrecciaMut;
 
 use tempfile::tempfile;
 
@@ -48,19 +46,8 @@ fn random_seeks(bencher: &mut Bencher) -> io::Result<()> {
 
     bencher.iter(|| {
         for (i, expected_offset) in &offsets[1000..2000] {
-            assert_eq!(b.binary_search(|offset, blob| {
-                let blob = (&blob[0 .. 8]).try_into().unwrap();
-                let found = u64::from_le_bytes(blob);
-
-                if *i == found {
-                    Ok(Some(offset))
-                } else if *i < found {
-                    Err(Search::Left)
-                } else { // if i > found
-                    Err(Search::Right)
-                }
-            }),
-            Some(*expected_offset));
+            let found = b.search_by_key(i, |blob| Some(u64::from_le_bytes(blob[0 .. 8].try_into().ok()?)));
+            assert_eq!(found.map(|(offset, _blob)| offset), Ok(*expected_offset));
         }
     });
 
@@ -0,0 +1,175 @@
+use std::fmt;
+use std::ops::{self, Range};
+use std::sync::Arc;
+
+use memmap2::Mmap;
+
+use super::Offset;
+
+/// A blob borrowed from the map of a `Breccia`.
+pub struct BlobRef<'a, H> {
+    offset: Offset<H>,
+    bytes: &'a [u8],
+    map: &'a Arc<Mmap>,
+}
+
+impl<'a, H> BlobRef<'a, H> {
+    pub(crate) fn new(offset: Offset<H>, bytes: &'a [u8], map: &'a Arc<Mmap>) -> Self {
+        Self { offset, bytes, map }
+    }
+
+    /// Converts this into an owned `Blob`, sharing the underlying map rather than copying.
+    pub fn to_blob(&self) -> Blob<H> {
+        let start = self.bytes.as_ptr() as usize - self.map.as_ptr() as usize;
+        Blob {
+            offset: self.offset,
+            map: Arc::clone(self.map),
+            range: start .. start + self.bytes.len(),
+        }
+    }
+
+    /// Returns the offset of this blob.
+    pub fn offset(&self) -> Offset<H> {
+        self.offset
+    }
+
+    /// Returns the length of this blob, in bytes.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns `true` if this blob is empty.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Returns the bytes of this blob.
+    ///
+    /// Unlike `Deref`, the returned slice borrows from the `Breccia`, not from this `BlobRef`.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+}
+
+impl<H> fmt::Debug for BlobRef<'_, H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BlobRef")
+         .field("offset", &self.offset)
+         .field("bytes", &self.bytes)
+         .finish()
+    }
+}
+
+impl<H> Clone for BlobRef<'_, H> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<H> Copy for BlobRef<'_, H> {
+}
+
+impl<H> PartialEq for BlobRef<'_, H> {
+    fn eq(&self, rhs: &Self) -> bool {
+        self.offset == rhs.offset && self.bytes == rhs.bytes
+    }
+}
+
+impl<H> Eq for BlobRef<'_, H> {
+}
+
+impl<H> ops::Deref for BlobRef<'_, H> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.bytes
+    }
+}
+
+impl<H> AsRef<[u8]> for BlobRef<'_, H> {
+    fn as_ref(&self) -> &[u8] {
+        self.bytes
+    }
+}
+
+/// A blob that keeps the map it was read from alive.
+///
+/// Unlike a `BlobRef`, a `Blob` does not borrow the `Breccia`, so it remains valid across a
+/// `reload`, and can be sent to other threads. With the `bytes` feature enabled it can also be
+/// converted into `bytes::Bytes` without copying.
+pub struct Blob<H> {
+    offset: Offset<H>,
+    map: Arc<Mmap>,
+    range: Range<usize>,
+}
+
+impl<H> Blob<H> {
+    /// Returns the offset of this blob.
+    pub fn offset(&self) -> Offset<H> {
+        self.offset
+    }
+
+    /// Returns the length of this blob, in bytes.
+    pub fn len(&self) -> usize {
+        self.range.len()
+    }
+
+    /// Returns `true` if this blob is empty.
+    pub fn is_empty(&self) -> bool {
+        self.range.is_empty()
+    }
+
+    /// Returns the bytes of this blob.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.map[self.range.clone()]
+    }
+}
+
+impl<H> fmt::Debug for Blob<H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Blob")
+         .field("offset", &self.offset)
+         .field("bytes", &self.as_bytes())
+         .finish()
+    }
+}
+
+impl<H> Clone for Blob<H> {
+    fn clone(&self) -> Self {
+        Self {
+            offset: self.offset,
+            map: Arc::clone(&self.map),
+            range: self.range.clone(),
+        }
+    }
+}
+
+impl<H> PartialEq for Blob<H> {
+    fn eq(&self, rhs: &Self) -> bool {
+        self.offset == rhs.offset && self.as_bytes() == rhs.as_bytes()
+    }
+}
+
+impl<H> Eq for Blob<H> {
+}
+
+impl<H> ops::Deref for Blob<H> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl<H> AsRef<[u8]> for Blob<H> {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl<H: 'static> From<Blob<H>> for bytes::Bytes {
+    fn from(blob: Blob<H>) -> Self {
+        bytes::Bytes::from_owner(blob)
+    }
+}
@@ -0,0 +1,37 @@
+use std::io;
+
+/// Errors that can occur when opening or creating a breccia.
+///
+/// `E` is the `DeserializeError` of the breccia's `Header`.
+#[derive(thiserror::Error, Debug)]
+pub enum Error<E> {
+    /// The file did not start with the expected magic bytes.
+    #[error("bad magic")]
+    BadMagic,
+
+    /// The header could not be deserialized.
+    #[error("header decode error")]
+    Header(#[source] E),
+
+    /// The padding bytes after the header were not all zero.
+    #[error("non-zero header padding")]
+    NonZeroPadding,
+
+    /// The file ended before the end of the header.
+    #[error("truncated header")]
+    TruncatedHeader,
+
+    /// An I/O error occurred.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl<E: 'static + std::error::Error + Send + Sync> From<Error<E>> for io::Error {
+    fn from(err: Error<E>) -> Self {
+        match err {
+            Error::Io(err) => err,
+            Error::TruncatedHeader => io::Error::new(io::ErrorKind::UnexpectedEof, err),
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::fmt;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use super::{Blob, Breccia, Header, Marker, Offset};
+
+/// How often `Follow` checks for new blobs if it isn't woken up sooner.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl<H: Header> Breccia<H> {
+    /// Returns a blocking iterator over the blobs starting at or after `from`, that waits for new
+    /// blobs to be appended once it reaches the end.
+    ///
+    /// Only committed blobs are returned, even if `set_read_uncommitted` is set. The breccia is
+    /// reloaded whenever the file changes. On Linux, inotify is used to wake up as soon as the
+    /// file is written to; elsewhere the file is polled.
+    ///
+    /// The iterator never returns `None`. Errors from reloading the file are returned, after
+    /// which iteration can continue.
+    pub fn follow(&mut self, from: Offset<H>) -> Follow<'_, H> {
+        Follow {
+            waker: Waker::new(self),
+            breccia: self,
+            next: from,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            reloaded: false,
+        }
+    }
+}
+
+/// A blocking iterator over new blobs, returned by `Breccia::follow`.
+pub struct Follow<'a, H> {
+    breccia: &'a mut Breccia<H>,
+
+    /// Where to look for the next blob.
+    next: Offset<H>,
+
+    waker: Waker,
+    poll_interval: Duration,
+
+    /// `true` if the breccia has been reloaded since the last blob was returned.
+    reloaded: bool,
+}
+
+impl<H> fmt::Debug for Follow<'_, H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Follow")
+            .field("next", &self.next)
+            .field("waker", &self.waker)
+            .field("poll_interval", &self.poll_interval)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<H: Header> Follow<'_, H> {
+    /// Sets how long to wait between checks for new blobs.
+    ///
+    /// When inotify is in use this is only a fallback, in case a write isn't noticed.
+    pub fn set_poll_interval(&mut self, poll_interval: Duration) {
+        self.poll_interval = poll_interval;
+    }
+
+    /// Returns the breccia being followed.
+    pub fn breccia(&self) -> &Breccia<H> {
+        self.breccia
+    }
+
+    fn next_committed(&mut self) -> Option<Blob<H>> {
+        let committed = self.breccia.committed()?;
+        if self.next > committed {
+            return None
+        }
+
+        let (offset, blob) = self.breccia.blobs_in(self.next .. committed).next()?;
+
+        // The blob's data follows its offset, padded to whole words, and then its end marker,
+        // which is where the next blob is searched for from.
+        self.next = offset + 1 + blob.len().div_ceil(size_of::<Marker>());
+        Some(self.breccia.blob_at(blob))
+    }
+}
+
+impl<H: Header> Iterator for Follow<'_, H> {
+    type Item = io::Result<Blob<H>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(blob) = self.next_committed() {
+                self.reloaded = false;
+                return Some(Ok(blob))
+            }
+
+            // The breccia may be out of date, so check once before waiting for further writes.
+            if self.reloaded {
+                self.waker.wait(self.poll_interval);
+            }
+
+            self.reloaded = true;
+            if let Err(err) = self.breccia.reload() {
+                return Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Waits for the file to be written to.
+#[derive(Debug)]
+enum Waker {
+    #[cfg(target_os = "linux")]
+    Inotify(std::os::fd::OwnedFd),
+    Poll,
+}
+
+impl Waker {
+    #[cfg(target_os = "linux")]
+    fn new<H>(breccia: &Breccia<H>) -> Self {
+        use std::ffi::CString;
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+        // Watching the file through /proc lets us follow it even if it has been renamed or
+        // unlinked since it was opened.
+        let path = CString::new(format!("/proc/self/fd/{}", breccia.fd.as_raw_fd()))
+                           .expect("no nul bytes");
+
+        // SAFETY: FFI calls with valid arguments. The fd is owned by the OwnedFd once created.
+        unsafe {
+            let fd = libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC);
+            if fd < 0 {
+                return Waker::Poll
+            }
+            let fd = OwnedFd::from_raw_fd(fd);
+
+            if libc::inotify_add_watch(fd.as_raw_fd(), path.as_ptr(), libc::IN_MODIFY) < 0 {
+                return Waker::Poll
+            }
+            Waker::Inotify(fd)
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn new<H>(_breccia: &Breccia<H>) -> Self {
+        Waker::Poll
+    }
+
+    /// Waits until the file may have been written to, or `timeout` has passed.
+    fn wait(&self, timeout: Duration) {
+        match self {
+            #[cfg(target_os = "linux")]
+            Waker::Inotify(fd) => {
+                use std::os::fd::AsRawFd;
+
+                let mut pollfd = libc::pollfd {
+                    fd: fd.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                let timeout = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+
+                // SAFETY: FFI calls with valid arguments. Errors, such as EINTR, just mean we
+                // check for new blobs early.
+                unsafe {
+                    libc::poll(&mut pollfd, 1, timeout);
+
+                    // Drain the events; we only care that there were some.
+                    let mut buf = [0u8; 4096];
+                    while libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) > 0 {
+                    }
+                }
+            },
+            Waker::Poll => thread::sleep(timeout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::sync::mpsc;
+
+    use tempfile::NamedTempFile;
+
+    use crate::BrecciaMut;
+
+    #[test]
+    fn follow_appended_blobs() -> io::Result<()> {
+        let path = NamedTempFile::new()?.into_temp_path();
+        let mut writer = BrecciaMut::create(&path, ())?;
+        let first = writer.write_blob(b"first")?;
+
+        let mut reader = Breccia::<()>::open(&path)?;
+        let (sender, receiver) = mpsc::channel();
+        let follower = thread::spawn(move || {
+            for blob in reader.follow(Offset::ZERO).take(4) {
+                sender.send(blob.map(|blob| blob.to_vec())).unwrap();
+            }
+        });
+
+        // Only write more once the follower has caught up, so that it has to wait for them.
+        assert_eq!(receiver.recv().unwrap()?, b"first");
+        writer.write_blob(&[])?;
+        assert_eq!(receiver.recv().unwrap()?, b"");
+
+        let mut batch = writer.start_batch()?;
+        batch.write_blob(&[0; 100])?;
+        batch.write_blob(&[2,0,0,0,0,0,0,0b1110_0000])?;
+        batch.commit()?;
+
+        assert_eq!(receiver.recv().unwrap()?, vec![0; 100]);
+        assert_eq!(receiver.recv().unwrap()?, vec![2,0,0,0,0,0,0,0b1110_0000]);
+        follower.join().unwrap();
+
+        // Following resumes from any offset.
+        let mut reader = Breccia::<()>::open(&path)?;
+        let mut follow = reader.follow(first + 1);
+        #[cfg(target_os = "linux")]
+        assert!(matches!(follow.waker, Waker::Inotify(_)));
+        assert_eq!(&*follow.next().unwrap()?, b"");
+        assert_eq!(&*follow.next().unwrap()?, &[0; 100]);
+        Ok(())
+    }
+
+    #[test]
+    fn follow_skips_uncommitted_blobs() -> io::Result<()> {
+        let path = NamedTempFile::new()?.into_temp_path();
+        let mut writer = BrecciaMut::create(&path, ())?;
+        let mut reader = Breccia::<()>::open(&path)?;
+        let mut follow = reader.follow(Offset::ZERO);
+
+        // Uncommitted blobs are never returned, even once they've hit the file. This is checked
+        // without a concurrent follower, as rolling back the batch while it reloads isn't safe.
+        let mut batch = writer.start_batch()?;
+        batch.write_blob(b"aborted")?;
+        batch.fd().flush()?;
+        follow.breccia.reload()?;
+        assert!(follow.next_committed().is_none());
+        batch.abort()?;
+
+        writer.write_blob(b"committed")?;
+        assert_eq!(&*follow.next().unwrap()?, b"committed");
+        Ok(())
+    }
+}
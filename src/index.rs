@@ -0,0 +1,411 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::{Breccia, Header, Marker, Offset};
+
+/// A key that can be stored in a `SparseIndex` sidecar file.
+pub trait IndexKey : Ord + Sized {
+    /// The serialized size, in bytes.
+    const SERIALIZED_SIZE: usize;
+
+    /// Serialize a key to bytes.
+    ///
+    /// `dst` will be a slice of exactly `SERIALIZED_SIZE` in length.
+    fn serialize(&self, dst: &mut [u8]);
+
+    /// The error returned when deserialize fails.
+    type DeserializeError: 'static + std::error::Error + Send + Sync;
+
+    /// Deserialize a key from bytes.
+    ///
+    /// `src` will be a slice of exactly `SERIALIZED_SIZE` in length.
+    fn deserialize(src: &[u8]) -> Result<Self, Self::DeserializeError>;
+}
+
+macro_rules! impl_index_key_for_ints {
+    ($($t:ty),*) => {$(
+        impl IndexKey for $t {
+            const SERIALIZED_SIZE: usize = size_of::<$t>();
+
+            fn serialize(&self, dst: &mut [u8]) {
+                dst.copy_from_slice(&self.to_le_bytes());
+            }
+
+            type DeserializeError = Infallible;
+            fn deserialize(src: &[u8]) -> Result<Self, Self::DeserializeError> {
+                Ok(Self::from_le_bytes(src.try_into().expect("correct length")))
+            }
+        }
+    )*}
+}
+
+impl_index_key_for_ints!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl<const N: usize> IndexKey for [u8; N] {
+    const SERIALIZED_SIZE: usize = N;
+
+    fn serialize(&self, dst: &mut [u8]) {
+        dst.copy_from_slice(self);
+    }
+
+    type DeserializeError = Infallible;
+    fn deserialize(src: &[u8]) -> Result<Self, Self::DeserializeError> {
+        Ok(src.try_into().expect("correct length"))
+    }
+}
+
+/// Magic bytes at the start of a sidecar file, including the format version.
+const SIDECAR_MAGIC: &[u8; 8] = b"\x00brxidx\x01";
+
+/// An in-memory index of sampled keys, used to narrow down searches of a sorted `Breccia`.
+///
+/// Roughly every `stride` words the key of a blob is sampled, so each search only has to touch
+/// the part of the file between two samples. Memory usage is thus traded for fewer page faults.
+///
+/// The index doesn't hold a reference to the breccia it was built from: the same breccia must be
+/// passed to every method. Call `update` after `Breccia::reload` to index new blobs; blobs
+/// written since the last update are still found, just without the help of the index.
+pub struct SparseIndex<H, K, F> {
+    stride: usize,
+
+    /// Sampled offsets and their keys, in order.
+    samples: Vec<(Offset<H>, K)>,
+
+    /// The end marker of the last blob indexed.
+    end: Offset<H>,
+
+    f: F,
+}
+
+impl<H, K: fmt::Debug, F> fmt::Debug for SparseIndex<H, K, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SparseIndex")
+            .field("stride", &self.stride)
+            .field("samples", &self.samples)
+            .field("end", &self.end)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<H: Header, K: Ord, F> SparseIndex<H, K, F>
+    where F: Fn(&[u8]) -> Option<K>
+{
+    /// Builds an index of `breccia`, sampling a key roughly every `stride` words.
+    ///
+    /// `f` extracts the key from a blob, as with `Breccia::search_by_key`. Blobs for which it
+    /// returns `None` are never sampled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `stride` is zero.
+    pub fn build(breccia: &Breccia<H>, stride: usize, f: F) -> Self {
+        assert!(stride > 0, "stride must be non-zero");
+
+        let mut index = Self {
+            stride,
+            samples: vec![],
+            end: Offset::ZERO,
+            f,
+        };
+        index.update(breccia);
+        index
+    }
+
+    /// Returns the number of sampled keys.
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Returns `true` if no keys have been sampled.
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Samples the blobs added to `breccia` since the index was built or last updated.
+    ///
+    /// If `breccia` is now shorter than the index, e.g. because uncommitted blobs that had been
+    /// indexed were rolled back by a repair, the index is rebuilt from scratch.
+    pub fn update(&mut self, breccia: &Breccia<H>) {
+        if breccia.end_offset() < self.end {
+            self.samples.clear();
+            self.end = Offset::ZERO;
+        }
+
+        let mut next_sample = self.samples.last().map_or(Offset::ZERO, |(offset, _)| *offset + self.stride);
+        for (offset, blob) in breccia.blobs_from(self.end) {
+            if offset >= next_sample && let Some(key) = (self.f)(blob) {
+                self.samples.push((offset, key));
+                next_sample = offset + self.stride;
+            }
+        }
+        self.end = breccia.end_offset();
+    }
+
+    /// Searches `breccia` for a key, using the index to narrow down the range searched.
+    ///
+    /// Returns the same results as `Breccia::search_by_key`.
+    pub fn search_by_key<'a>(&self, breccia: &'a Breccia<H>, key: &K) -> Result<(Offset<H>, &'a [u8]), Offset<H>> {
+        let end = breccia.end_offset();
+
+        // Ignore samples of blobs that are no longer visible.
+        let samples = &self.samples[.. self.samples.partition_point(|(offset, _)| *offset < end)];
+
+        let i = samples.partition_point(|(_, k)| k < key);
+        let start = match i.checked_sub(1) {
+            Some(prev) => samples[prev].0 + 1,
+            None => Offset::ZERO,
+        };
+
+        let mut search_end = samples.get(i).map_or(end, |(offset, _)| *offset);
+        if let Some((offset, k)) = samples.get(i) && k == key {
+            if let Ok(blob) = breccia.get_blob(*offset) &&
+               (self.f)(blob.as_bytes()).as_ref() == Some(key)
+            {
+                return Ok((*offset, blob.as_bytes()))
+            }
+
+            // The sample doesn't match the breccia, so search up to the next sample instead.
+            search_end = samples.get(i + 1).map_or(end, |(offset, _)| *offset);
+        }
+        breccia.search_in_range_by(|blob| (self.f)(blob).map(|k| k.cmp(key)), start .. search_end)
+    }
+}
+
+impl<H: Header, K: IndexKey, F> SparseIndex<H, K, F>
+    where F: Fn(&[u8]) -> Option<K>
+{
+    /// Saves the index to a sidecar file.
+    ///
+    /// The length of the indexed part of the breccia is saved too, so that `load` can detect a
+    /// sidecar that doesn't match the file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut fd = BufWriter::new(File::create(path)?);
+
+        fd.write_all(SIDECAR_MAGIC)?;
+        fd.write_all(&(self.stride as u64).to_le_bytes())?;
+        fd.write_all(&(self.end.to_file_offset() + size_of::<Marker>() as u64).to_le_bytes())?;
+        fd.write_all(&(self.samples.len() as u64).to_le_bytes())?;
+
+        let mut key_buf = vec![0; K::SERIALIZED_SIZE];
+        for (offset, key) in &self.samples {
+            fd.write_all(&offset.to_u64().to_le_bytes())?;
+            key.serialize(&mut key_buf);
+            fd.write_all(&key_buf)?;
+        }
+
+        fd.into_inner()?.sync_all()
+    }
+
+    /// Loads an index from a sidecar file written by `save`, and updates it with any blobs added
+    /// to `breccia` since.
+    ///
+    /// Returns `Ok(None)` if the sidecar doesn't match `breccia`: if it indexes more of the file
+    /// than is visible, doesn't end at a marker, or has a sample that isn't a blob with the
+    /// sampled key. An `InvalidData` error is returned if the sidecar itself is corrupt.
+    ///
+    /// Each sampled blob is read to check its key, but unlike `build` the rest of the indexed part
+    /// of the file isn't.
+    pub fn load<P: AsRef<Path>>(path: P, breccia: &Breccia<H>, f: F) -> io::Result<Option<Self>> {
+        fn read_u64(fd: &mut impl Read) -> io::Result<u64> {
+            let mut buf = [0; 8];
+            fd.read_exact(&mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        }
+
+        fn invalid(msg: &str) -> io::Error {
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        }
+
+        let mut fd = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; SIDECAR_MAGIC.len()];
+        fd.read_exact(&mut magic)?;
+        if &magic != SIDECAR_MAGIC {
+            return Err(invalid("bad sparse index magic"))
+        }
+
+        let stride = usize::try_from(read_u64(&mut fd)?).ok()
+                                                         .filter(|stride| *stride > 0)
+                                                         .ok_or_else(|| invalid("bad sparse index stride"))?;
+
+        let len = read_u64(&mut fd)?;
+        let Some(end) = len.checked_sub(size_of::<Marker>() as u64)
+                           .and_then(|end| Offset::<H>::try_from_file_offset(end).ok()) else {
+            return Err(invalid("bad sparse index file length"))
+        };
+
+        let is_marker = breccia.visible_map().get(end.raw)
+                                             .is_some_and(|word| word.offset::<H>() == end);
+        if !is_marker {
+            return Ok(None)
+        }
+
+        let count = read_u64(&mut fd)?;
+        let mut samples = Vec::new();
+        let mut key_buf = vec![0; K::SERIALIZED_SIZE];
+        for _ in 0 .. count {
+            let offset = Offset::from_u64(read_u64(&mut fd)?)
+                             .filter(|offset| *offset < end)
+                             .filter(|offset| samples.last().is_none_or(|(prev, _)| prev < offset))
+                             .ok_or_else(|| invalid("bad sparse index sample offset"))?;
+
+            fd.read_exact(&mut key_buf)?;
+            let key = K::deserialize(&key_buf).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+            let matches = breccia.get_blob(offset)
+                                 .is_ok_and(|blob| f(blob.as_bytes()).as_ref() == Some(&key));
+            if !matches {
+                return Ok(None)
+            }
+            samples.push((offset, key));
+        }
+
+        let mut index = Self { stride, samples, end, f };
+        index.update(breccia);
+        Ok(Some(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::{NamedTempFile, tempfile};
+
+    use crate::BrecciaMut;
+    use crate::test_util::{assert_search_result, key, write_keys};
+
+    type KeyFn = fn(&[u8]) -> Option<u32>;
+
+    fn build(b: &Breccia, stride: usize) -> SparseIndex<(), u32, KeyFn> {
+        SparseIndex::build(b, stride, key)
+    }
+
+    fn load(path: &Path, b: &Breccia) -> io::Result<Option<SparseIndex<(), u32, KeyFn>>> {
+        SparseIndex::load(path, b, key)
+    }
+
+    fn assert_same_results(b: &Breccia, index: &SparseIndex<(), u32, KeyFn>, targets: std::ops::Range<u32>) {
+        for target in targets {
+            assert_search_result(b, target, index.search_by_key(b, &target));
+        }
+    }
+
+    #[test]
+    fn search_with_index() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, ())?;
+        write_keys(&mut b, (0 .. 500).map(|i| i * 2), 0)?;
+
+        let index = build(&b, 64);
+        assert!(index.len() > 10);
+        assert_same_results(&b, &index, 0 .. 1002);
+
+        // The index bounds each search to the blobs between two samples.
+        let probes = std::cell::Cell::new(0);
+        let counting = SparseIndex::build(&b, 64, |blob: &[u8]| { probes.set(probes.get() + 1); key(blob) });
+        probes.set(0);
+        assert!(counting.search_by_key(&b, &501).is_err());
+        let indexed_probes = probes.get();
+
+        probes.set(0);
+        assert!(b.search_by_key(&501, |blob| { probes.set(probes.get() + 1); key(blob) }).is_err());
+        assert!(indexed_probes < probes.get(), "{indexed_probes} vs {} probes", probes.get());
+
+        Ok(())
+    }
+
+    #[test]
+    fn search_other_breccia() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, ())?;
+        write_keys(&mut b, 0 .. 100, 0)?;
+        let index = build(&b, 16);
+
+        // Searching a breccia the index wasn't built from gives wrong results, but doesn't panic.
+        let mut other = BrecciaMut::create_from_file(tempfile()?, ())?;
+        other.write_blob(&[0; 100])?;
+        write_keys(&mut other, 0 .. 100, 0)?;
+        for target in 0 .. 100 {
+            if let Ok((_, blob)) = index.search_by_key(&other, &target) {
+                assert_eq!(key(blob), Some(target));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn update_after_appends() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, ())?;
+        let mut index = build(&b, 16);
+        assert!(index.is_empty());
+
+        write_keys(&mut b, 0 .. 100, 0)?;
+
+        // Blobs written since the index was built are still found.
+        assert_same_results(&b, &index, 0 .. 101);
+
+        index.update(&b);
+        let len = index.len();
+        assert!(len > 10);
+        assert_same_results(&b, &index, 0 .. 101);
+
+        write_keys(&mut b, 100 .. 200, 0)?;
+        index.update(&b);
+        assert!(index.len() > len);
+        assert_eq!(index.samples, build(&b, 16).samples);
+        assert_same_results(&b, &index, 0 .. 201);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sidecar() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, ())?;
+        write_keys(&mut b, 0 .. 100, 0)?;
+        let index = build(&b, 16);
+
+        let sidecar = NamedTempFile::new()?;
+        index.save(sidecar.path())?;
+
+        let loaded = load(sidecar.path(), &b)?.unwrap();
+        assert_eq!(loaded.samples, index.samples);
+        assert_eq!(loaded.end, index.end);
+
+        // Blobs appended after saving are indexed on load.
+        write_keys(&mut b, 100 .. 200, 0)?;
+        let loaded = load(sidecar.path(), &b)?.unwrap();
+        assert_eq!(loaded.samples, build(&b, 16).samples);
+        assert_same_results(&b, &loaded, 0 .. 201);
+
+        // A sidecar for a longer file doesn't match.
+        let other = BrecciaMut::create_from_file(tempfile()?, ())?;
+        assert!(load(sidecar.path(), &other)?.is_none());
+
+        // Nor does one whose samples don't match the blobs at their offsets. The first sample's
+        // offset is at byte 32, followed by its key.
+        let bytes = std::fs::read(sidecar.path())?;
+        let first_offset = u64::from_le_bytes(bytes[32 .. 40].try_into().unwrap());
+        let first_key = u32::from_le_bytes(bytes[40 .. 44].try_into().unwrap());
+
+        let mut bad_offset = bytes.clone();
+        bad_offset[32 .. 40].copy_from_slice(&(first_offset + 1).to_le_bytes());
+        std::fs::write(sidecar.path(), &bad_offset)?;
+        assert!(load(sidecar.path(), &b)?.is_none());
+
+        let mut bad_key = bytes.clone();
+        bad_key[40 .. 44].copy_from_slice(&(first_key + 1).to_le_bytes());
+        std::fs::write(sidecar.path(), &bad_key)?;
+        assert!(load(sidecar.path(), &b)?.is_none());
+
+        // Corrupt sidecars are errors.
+        let mut bytes = bytes;
+        bytes[0] = 0xff;
+        std::fs::write(sidecar.path(), &bytes)?;
+        let err = load(sidecar.path(), &b).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        Ok(())
+    }
+}
@@ -6,11 +6,18 @@ use std::io::{self, BufWriter, Read, Write, Seek, SeekFrom};
 use std::ops::{self, Range};
 use std::path::Path;
 use std::ptr;
+use std::sync::Arc;
 
 use memmap2::Mmap;
 
+mod blob;
+pub use blob::{Blob, BlobRef};
+
+mod error;
+pub use error::Error;
+
 mod offset;
-pub use offset::Offset;
+pub use offset::{Offset, TryFromFileOffsetError};
 
 mod header;
 use header::HeaderExt;
@@ -19,6 +26,27 @@ pub use header::Header;
 mod marker;
 use marker::{Marker, State::Clean, State::Dirty};
 
+mod verify;
+pub use verify::{Problem, ProblemKind, VerifyReport};
+
+mod search;
+pub use search::RangeByKey;
+
+mod index;
+pub use index::{IndexKey, SparseIndex};
+
+mod follow;
+pub use follow::Follow;
+
+mod sorted;
+pub use sorted::{SortViolation, SortedBatch, SortedBrecciaMut, SortedWriteError};
+
+mod sort;
+pub use sort::{SortOptions, sort_into, sort_into_with_options};
+
+#[cfg(test)]
+mod test_util;
+
 const _: () = {
     if size_of::<usize>() != 8 {
         panic!("only 64-bit platforms are supported")
@@ -29,9 +57,12 @@ const _: () = {
 #[derive(Debug)]
 pub struct Breccia<H = ()> {
     header: H,
-    map: Mmap,
+    map: Arc<Mmap>,
     markers: *const [Marker],
     fd: File,
+    tail: Tail,
+    committed: Option<Offset<H>>,
+    read_uncommitted: bool,
 }
 
 // SAFETY: the markers pointer is only ever used to create a &[Marker] slice
@@ -69,21 +100,56 @@ impl<H> Breccia<H> {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The state of the end of a breccia file, as of the last `open` or `reload`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Tail {
+    /// The file ends with the end marker of a committed batch.
+    Clean,
+
+    /// The file ends with a complete end marker, but the batch it belongs to was never committed.
+    Dirty,
+
+    /// The file ends part-way through a blob.
+    Torn,
+
+    /// The file ends part-way through a word.
+    Unaligned,
+}
+
+/// Error returned by `Breccia::get_blob`.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GetBlobError {
     /// The offset is beyond the range of the breccia.
+    #[error("offset out of range")]
     OutOfRange,
 
     /// There is no blob starting at the provided offset; the offset points to either the middle of
     /// a different blob, or padding data.
+    #[error("offset unaligned")]
     Unaligned,
+
+    /// The blob at this offset would contain a padding marker.
+    ///
+    /// Padding markers only ever appear between a blob's start marker and its data; the blob
+    /// itself starts at the last padding marker.
+    #[error("padding marker inside blob")]
+    PaddingInBlob,
+
+    /// The end marker has a padding length longer than the blob itself.
+    #[error("bad padding length")]
+    BadPaddingLength,
+
+    /// The blob has no end marker, either because it's still being written, or because the write
+    /// was interrupted.
+    #[error("blob not terminated by an end marker")]
+    Unterminated,
 }
 
 
 impl<H: Header> Breccia<H> {
-    fn try_map_to_markers_slice(map: &Mmap) -> io::Result<*const [Marker]> {
+    fn try_map_to_markers_slice(map: &Mmap) -> Result<*const [Marker], Error<H::DeserializeError>> {
         let marker_slice = map.get(H::SIZE_WITH_PADDING ..)
-                              .unwrap(); // TODO: actually handle this error
+                              .ok_or(Error::TruncatedHeader)?;
 
         Ok(ptr::slice_from_raw_parts(
             marker_slice.as_ptr() as *const Marker,
@@ -91,109 +157,288 @@ impl<H: Header> Breccia<H> {
         ))
     }
 
+    /// Determines the state of the end of the file, and the last committed end marker.
+    fn find_tail(map: &Mmap, markers: &[Marker]) -> (Tail, Option<Offset<H>>) {
+        let committed = markers.iter()
+                               .enumerate()
+                               .rev()
+                               .find(|(i, marker)| marker.offset::<H>().raw == *i && marker.state() == Clean)
+                               .map(|(i, _marker)| Offset::new(i));
+
+        let tail = if !(map.len() - H::SIZE_WITH_PADDING).is_multiple_of(size_of::<Marker>()) {
+            Tail::Unaligned
+        } else if let Some(last) = markers.len().checked_sub(1) &&
+                  is_end_marker::<H>(markers, last)
+        {
+            if committed == Some(Offset::new(last)) {
+                Tail::Clean
+            } else {
+                Tail::Dirty
+            }
+        } else {
+            Tail::Torn
+        };
+
+        (tail, committed)
+    }
+
     /// Opens an existing breccia file.
-    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error<H::DeserializeError>> {
         Self::open_file(File::open(path)?)
     }
 
-    fn open_file(mut fd: File) -> io::Result<Self> {
+    fn open_file(mut fd: File) -> Result<Self, Error<H::DeserializeError>> {
+        fn read_header(fd: &mut File, buf: &mut [u8]) -> io::Result<bool> {
+            match fd.read_exact(buf) {
+                Ok(()) => Ok(true),
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+                Err(err) => Err(err),
+            }
+        }
+
         fd.seek(SeekFrom::Start(0))?;
 
         let mut actual_magic = vec![0u8; H::MAGIC.len()];
-        fd.read_exact(&mut actual_magic)?;
+        if !read_header(&mut fd, &mut actual_magic)? {
+            return Err(Error::TruncatedHeader);
+        }
 
         if actual_magic != H::MAGIC {
-            return Err(io::Error::other("bad magic"));
+            return Err(Error::BadMagic);
         }
 
-        let mut header_bytes = vec![0u8; H::SIZE_WITH_PADDING - H::MAGIC.len()];
-        fd.read_exact(&mut header_bytes)?;
-        let header = H::deserialize(&header_bytes).map_err(io::Error::other)?;
+        let mut header_bytes = vec![0u8; H::SERIALIZED_SIZE];
+        if !read_header(&mut fd, &mut header_bytes)? {
+            return Err(Error::TruncatedHeader);
+        }
+        let header = H::deserialize(&header_bytes).map_err(Error::Header)?;
 
         let padding = &mut [0u8; size_of::<Marker>()][0 .. H::PADDING_SIZE];
-        fd.read_exact(padding)?;
-
-        // FIXME: validate that padding bytes are all zero
+        if !read_header(&mut fd, padding)? {
+            return Err(Error::TruncatedHeader);
+        }
 
-        // FIXME: check if last blob was written cleanly
+        if padding.iter().any(|b| *b != 0) {
+            return Err(Error::NonZeroPadding);
+        }
 
-        let map = unsafe {
+        let map = Arc::new(unsafe {
             Mmap::map(&fd)?
-        };
+        });
+
+        let markers = Self::try_map_to_markers_slice(&map)?;
+        let (tail, committed) = Self::find_tail(&map, unsafe { &*markers });
 
         Ok(Self {
             header,
-            markers: Self::try_map_to_markers_slice(&map)?,
+            markers,
             map,
             fd,
+            tail,
+            committed,
+            read_uncommitted: false,
         })
     }
 
+    /// Returns the state of the end of the file, as of the last `open` or `reload`.
+    pub fn tail(&self) -> Tail {
+        self.tail
+    }
+
+    /// Returns the offset of the last end marker written by a committed batch.
+    ///
+    /// Everything up to and including this marker is trustworthy. Returns `None` if not even the
+    /// initial marker was written.
+    pub fn committed(&self) -> Option<Offset<H>> {
+        self.committed
+    }
+
+    /// Returns the number of bytes at the start of the file that are covered by committed batches.
+    pub fn committed_len(&self) -> u64 {
+        match self.committed {
+            Some(committed) => committed.to_file_offset() + size_of::<Marker>() as u64,
+            None => H::SIZE_WITH_PADDING as u64,
+        }
+    }
+
     fn map(&self) -> &[Marker] {
         unsafe {
             &*self.markers
         }
     }
 
+    /// Returns the part of the map visible to readers.
+    fn visible_map(&self) -> &[Marker] {
+        if self.read_uncommitted {
+            self.map()
+        } else {
+            &self.map()[.. self.committed.map_or(0, |committed| committed.raw + 1)]
+        }
+    }
+
+    /// Sets whether blobs written by uncommitted batches are visible.
+    ///
+    /// By default only committed blobs are visible, so iteration, search, and lookups never see
+    /// part of a batch.
+    pub fn set_read_uncommitted(&mut self, read_uncommitted: bool) {
+        self.read_uncommitted = read_uncommitted;
+    }
+
+    /// Returns `true` if blobs written by uncommitted batches are visible.
+    pub fn reads_uncommitted(&self) -> bool {
+        self.read_uncommitted
+    }
+
     /// Gets the blob at an offset.
-    pub fn get_blob(&mut self, offset: Offset<H>) -> Result<&[u8], GetBlobError> {
-        let first_mark = self.map().get(offset.raw)
-                                   .ok_or(GetBlobError::OutOfRange)?;
-
-        if first_mark.offset() == offset {
-            let end_offset = offset + 1;
-
-            while let Some(potential_mark) = self.map().get(end_offset.raw) {
-                if potential_mark.is_padding() {
-                    todo!("blob is invalid");
-                } else if potential_mark.offset() == end_offset {
-                    // We're at the end of the blob.
-                    let blob = Marker::slice_to_bytes(&self.map()[offset.raw + 1 .. end_offset.raw]);
-
-                    if let Some(blob_len) = blob.len().checked_sub(potential_mark.padding_len()) {
-                        let (blob, _padding) = blob.split_at(blob_len);
-                        return Ok(blob);
-                    } else {
-                        todo!("handle incorrect padding length")
-                    }
+    pub fn get_blob(&self, offset: Offset<H>) -> Result<BlobRef<'_, H>, GetBlobError> {
+        let map = self.visible_map();
+        let first_mark = map.get(offset.raw)
+                            .ok_or(GetBlobError::OutOfRange)?;
+
+        if first_mark.offset() != offset {
+            return Err(GetBlobError::Unaligned);
+        }
+
+        let mut end_offset = offset + 1;
+        if end_offset.raw == map.len() {
+            // This is the last marker, so no blob starts here (yet).
+            return Err(GetBlobError::OutOfRange);
+        }
+
+        while let Some(potential_mark) = map.get(end_offset.raw) {
+            if potential_mark.offset() == end_offset {
+                if end_offset == offset + 1 && potential_mark.is_padding() {
+                    return Err(GetBlobError::PaddingInBlob);
                 }
+
+                // We're at the end of the blob.
+                let blob = Marker::slice_to_bytes(&map[offset.raw + 1 .. end_offset.raw]);
+
+                let blob_len = blob.len().checked_sub(potential_mark.padding_len())
+                                         .ok_or(GetBlobError::BadPaddingLength)?;
+                let (blob, _padding) = blob.split_at(blob_len);
+                return Ok(BlobRef::new(offset, blob, &self.map));
             }
-            todo!("last blob not fully written")
-        } else {
-            Err(GetBlobError::Unaligned)
+            end_offset += 1;
         }
+        Err(GetBlobError::Unterminated)
+    }
+
+    /// Converts a blob returned by `blobs` or one of its variants, or by a search, into an owned
+    /// `Blob`, without looking it up again.
+    ///
+    /// The offset of the blob is worked out from where `blob` lies within the map.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `blob` isn't a whole blob within the map of this `Breccia`: it must start just
+    /// after a marker, and end at an end marker whose padding matches its length.
+    pub fn blob_at(&self, blob: &[u8]) -> Blob<H> {
+        let markers = self.map();
+        let start = (blob.as_ptr() as usize).wrapping_sub(markers.as_ptr() as usize);
+
+        let data = start / size_of::<Marker>();
+        let end = data + blob.len().div_ceil(size_of::<Marker>());
+        let is_blob = start.is_multiple_of(size_of::<Marker>()) &&
+                      data > 0 && end < markers.len() &&
+                      markers[data - 1].offset::<H>().raw == data - 1 &&
+                      markers[end].offset::<H>().raw == end &&
+                      markers[end].padding_len() == (end - data) * size_of::<Marker>() - blob.len();
+        assert!(is_blob, "blob is not in this breccia's map");
+
+        BlobRef::new(Offset::new(data - 1), blob, &self.map).to_blob()
     }
 
     /// Reloads the `Breccia` to reflect newly written blobs.
-    pub fn reload(&mut self) -> io::Result<()> {
-        // FIXME: check if last blob was written cleanly
-        let new_map = unsafe {
+    ///
+    /// Returns a summary of the blobs that were committed since the last reload.
+    pub fn reload(&mut self) -> io::Result<ReloadSummary<'_, H>> {
+        let old_end = self.committed().unwrap_or(Offset::ZERO);
+
+        let new_map = Arc::new(unsafe {
             Mmap::map(&self.fd)?
-        };
+        });
 
         let new_markers = Self::try_map_to_markers_slice(&new_map)?;
+        let (tail, committed) = Self::find_tail(&new_map, unsafe { &*new_markers });
 
         self.map = new_map;
         self.markers = new_markers;
+        self.tail = tail;
+        self.committed = committed;
 
-        Ok(())
+        Ok(ReloadSummary {
+            breccia: self,
+            old_end,
+        })
+    }
+}
+
+/// What changed when a `Breccia` was reloaded.
+pub struct ReloadSummary<'a, H> {
+    breccia: &'a Breccia<H>,
+    old_end: Offset<H>,
+}
+
+impl<H: Header> fmt::Debug for ReloadSummary<'_, H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReloadSummary")
+            .field("old_end", &self.old_end)
+            .field("new_end", &self.new_end())
+            .finish()
+    }
+}
+
+impl<'a, H: Header> ReloadSummary<'a, H> {
+    /// Returns the offset of the last committed end marker before the reload.
+    pub fn old_end(&self) -> Offset<H> {
+        self.old_end
+    }
+
+    /// Returns the offset of the last committed end marker after the reload.
+    pub fn new_end(&self) -> Offset<H> {
+        self.breccia.committed().unwrap_or(Offset::ZERO)
+    }
+
+    /// Returns `true` if any blobs were committed since the last reload.
+    pub fn has_new_blobs(&self) -> bool {
+        self.new_end() > self.old_end
+    }
+
+    /// Returns an iterator over the blobs committed since the last reload.
+    ///
+    /// Uncommitted blobs are never included, even if the breccia reads uncommitted blobs.
+    pub fn new_blobs(&self) -> Blobs<'a, H> {
+        let new_end = self.new_end();
+        self.breccia.blobs_in(self.old_end.min(new_end) .. new_end)
+    }
+
+    /// Returns the number of blobs committed since the last reload.
+    ///
+    /// The new blobs are counted when this is called, taking time proportional to their size.
+    pub fn new_blob_count(&self) -> usize {
+        self.new_blobs().count()
     }
 }
 
 impl<H: Header> BrecciaMut<H> {
     /// Creates a new breccia file.
-    pub fn create<P: AsRef<Path>>(path: P, header: H) -> io::Result<Self> {
+    ///
+    /// If a file already exists at `path` it is truncated, discarding its contents. Otherwise the
+    /// new breccia would be followed by whatever the old file held past its header.
+    pub fn create<P: AsRef<Path>>(path: P, header: H) -> Result<Self, Error<H::DeserializeError>> {
         let fd = OpenOptions::new()
                     .read(true)
                     .write(true)
                     .create(true)
+                    .truncate(true)
                     .open(path)?;
 
         Self::create_from_file(fd, header)
     }
 
     /// Opens an existing breccia file.
-    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error<H::DeserializeError>> {
         let fd = OpenOptions::new()
                     .read(true)
                     .write(true)
@@ -205,7 +450,7 @@ impl<H: Header> BrecciaMut<H> {
     }
 
     /// Creates a new breccia from a `File`.
-    pub fn create_from_file(mut fd: File, header: H) -> io::Result<Self> {
+    pub fn create_from_file(mut fd: File, header: H) -> Result<Self, Error<H::DeserializeError>> {
         fd.seek(SeekFrom::Start(0))?;
         fd.write_all(H::MAGIC)?;
 
@@ -213,8 +458,8 @@ impl<H: Header> BrecciaMut<H> {
         header.serialize(&mut header_bytes);
         fd.write_all(&header_bytes)?;
 
-        let mut padding = &[0; size_of::<Marker>()][0 .. H::PADDING_SIZE];
-        fd.write_all(&mut padding)?;
+        let padding = &[0; size_of::<Marker>()][0 .. H::PADDING_SIZE];
+        fd.write_all(padding)?;
 
         fd.write_all(&Marker::new(Offset::<H>::new(0), 0, Clean).to_bytes())?;
 
@@ -236,16 +481,192 @@ impl<H: Header> BrecciaMut<H> {
     }
 
     /// Starts a new `Batch` of blobs.
+    ///
+    /// Fails if the breccia ends with data from a batch that was never committed; use `recover` to
+    /// discard it first.
     pub fn start_batch<'a>(&'a mut self) -> io::Result<Batch<'a, H>> {
         Batch::new(self)
     }
+
+    /// Recovers from a batch that was interrupted before it was committed.
+    ///
+    /// Everything after the last clean end marker is truncated, allowing new batches to be
+    /// written. If the breccia was already clean, nothing is changed.
+    ///
+    /// Equivalent to `repair(RepairMode::Truncate)`.
+    pub fn recover(&mut self) -> io::Result<Recovery<H>> {
+        self.repair(RepairMode::Truncate)
+    }
+
+    /// Repairs a breccia with uncommitted data at the end, returning what was discarded.
+    ///
+    /// Changes are synced to disk before returning. If the breccia was already clean, nothing is
+    /// changed.
+    ///
+    /// Like rolling back a `Batch`, truncating the file can crash concurrent readers with
+    /// `SIGBUS`, so only repair a breccia that nothing else is reading.
+    pub fn repair(&mut self, mode: RepairMode) -> io::Result<Recovery<H>> {
+        self.reload()?;
+        let file_len = self.fd.metadata()?.len();
+
+        let committed = self.committed
+                            .ok_or_else(|| io::Error::other("no committed end marker found"))?;
+
+        let end = match mode {
+            RepairMode::Truncate => committed,
+            RepairMode::Commit => match self.tail {
+                Tail::Clean => committed,
+                Tail::Dirty => Offset::new(self.map().len() - 1),
+                Tail::Torn | Tail::Unaligned => {
+                    // Committing only part of the batch would break its atomicity.
+                    return Err(io::Error::other("uncommitted batch is incomplete; use RepairMode::Truncate"))
+                },
+            },
+        };
+
+        let lost_blobs = Blobs::new(&self.map()[end.raw ..], end)
+                               .map(|(offset, _blob)| offset)
+                               .collect();
+
+        // Read the end marker before truncating, as afterwards the truncated part of the map is
+        // no longer valid.
+        let mut end_marker = self.map()[end.raw];
+
+        let end_len = end.to_file_offset() + size_of::<Marker>() as u64;
+        if file_len != end_len {
+            self.fd.set_len(end_len)?;
+        }
+
+        if end_marker.state() == Dirty {
+            end_marker.set_state(Clean);
+            self.fd.seek(SeekFrom::Start(end.to_file_offset()))?;
+            self.fd.write_all(&end_marker.to_bytes())?;
+        }
+
+        if end != committed || file_len != end_len {
+            self.fd.sync_all()?;
+            self.reload()?;
+        }
+
+        Ok(Recovery {
+            committed: end,
+            lost_blobs,
+            lost_bytes: file_len - end_len,
+        })
+    }
+}
+
+/// How `BrecciaMut::repair` treats uncommitted data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RepairMode {
+    /// Truncate everything after the last clean end marker, discarding all uncommitted blobs.
+    Truncate,
+
+    /// Keep all uncommitted blobs, marking the last end marker as clean.
+    ///
+    /// Fails, changing nothing, unless the file ends with a complete end marker (`Tail::Dirty`), as
+    /// otherwise the interrupted batch was only partially written. Even then, only use this if
+    /// the batch is known to have been complete apart from the commit itself.
+    Commit,
+}
+
+/// The uncommitted data discarded by `BrecciaMut::repair`.
+#[derive(Debug)]
+pub struct Recovery<H> {
+    /// The offset of the last committed end marker, which is now the end of the breccia.
+    pub committed: Offset<H>,
+
+    /// The offsets of complete, but uncommitted, blobs that were discarded.
+    pub lost_blobs: Vec<Offset<H>>,
+
+    /// The total number of bytes truncated, including partially written blobs.
+    pub lost_bytes: u64,
+}
+
+impl<H> Recovery<H> {
+    /// Returns `true` if nothing had to be discarded.
+    pub fn is_clean(&self) -> bool {
+        self.lost_bytes == 0
+    }
 }
 
 
 impl<H: Header> Breccia<H> {
-    /// Returns an iterator over all blobs stored.
+    /// Returns an iterator over all visible blobs.
     pub fn blobs<'a>(&'a self) -> Blobs<'a, H> {
-        Blobs::new(self.map(), Offset::new(0))
+        Blobs::new(self.visible_map(), Offset::new(0))
+    }
+
+    /// Returns an iterator over the visible blobs starting at or after `offset`.
+    ///
+    /// `offset` does not need to be the offset of a blob: the first blob after it is found.
+    pub fn blobs_from<'a>(&'a self, offset: Offset<H>) -> Blobs<'a, H> {
+        let map = self.visible_map();
+        let start = offset.raw.min(map.len());
+        Blobs::new(&map[start ..], Offset::new(start))
+    }
+
+    /// Returns an iterator over the visible blobs ending at or before `offset`.
+    ///
+    /// A blob ends at its end marker, which is also the start of the next blob. Thus
+    /// `blobs_before(offset)` and `blobs_from(offset)` never return the same blob.
+    pub fn blobs_before<'a>(&'a self, offset: Offset<H>) -> Blobs<'a, H> {
+        self.blobs_in(Offset::new(0) .. offset)
+    }
+
+    /// Finds the first visible blob that starts at or after a position in the file.
+    ///
+    /// `file_offset` can be any position, including within the header, or in the middle of a
+    /// blob.
+    pub fn next_blob_at_or_after(&self, file_offset: u64) -> Option<(Offset<H>, &[u8])> {
+        let offset = Self::word_at_or_after(file_offset)?;
+        self.blobs_from(offset).next()
+    }
+
+    /// Finds the last visible blob that starts before a position in the file.
+    ///
+    /// `file_offset` can be any position, including within the header, or in the middle of a
+    /// blob.
+    pub fn prev_blob_before(&self, file_offset: u64) -> Option<(Offset<H>, &[u8])> {
+        let map = self.visible_map();
+        let start = Self::word_at_or_after(file_offset).map_or(map.len(), |offset| offset.raw.min(map.len()));
+
+        // Blobs that start before `start` end at or before the first marker after it.
+        let end = (start .. map.len()).find(|i| map[*i].offset::<H>().raw == *i)
+                                      .unwrap_or(map.len());
+        self.blobs_before(Offset::new(end)).next_back()
+    }
+
+    /// Finds the first word at or after a position in the file.
+    ///
+    /// Returns `None` if there is no such word.
+    fn word_at_or_after(file_offset: u64) -> Option<Offset<H>> {
+        // The header is padded to a multiple of the word size, so this is always a word boundary.
+        let file_offset = file_offset.checked_next_multiple_of(size_of::<Marker>() as u64)?;
+
+        match Offset::try_from_file_offset(file_offset) {
+            Ok(offset) => Some(offset),
+            Err(TryFromFileOffsetError::WithinHeader) => Some(Offset::new(0)),
+            Err(TryFromFileOffsetError::Unaligned) => unreachable!("file offset rounded up to a word"),
+            Err(TryFromFileOffsetError::TooLarge) => None,
+        }
+    }
+
+    /// Returns an iterator over the visible blobs that start at or after `range.start`, and end at
+    /// or before `range.end`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if range `start > end`.
+    pub fn blobs_in<'a>(&'a self, range: Range<Offset<H>>) -> Blobs<'a, H> {
+        if range.start.raw > range.end.raw {
+            panic!("range.start > range.end")
+        }
+
+        let map = self.visible_map();
+        let end = range.end.raw.saturating_add(1).min(map.len());
+        let start = range.start.raw.min(end);
+        Blobs::new(&map[start .. end], Offset::new(start))
     }
 }
 
@@ -281,6 +702,15 @@ impl<'a, H: Header> Blobs<'a, H> {
             }
         }
 
+        // Find the last marker, skipping any partially written blob at the end.
+        while let Some((potential_marker, rest)) = map.split_last() {
+            if potential_marker.offset() == offset + rest.len() {
+                break
+            } else {
+                map = rest;
+            }
+        }
+
         Self {
             offset,
             map,
@@ -322,9 +752,9 @@ impl<H: Header> std::iter::FusedIterator for Blobs<'_, H> {
 
 impl<'a, H: Header> std::iter::DoubleEndedIterator for Blobs<'a, H> {
     fn next_back(&mut self) -> Option<Self::Item> {
-        // Consume padding
         loop {
-            if let &[.., maybe_marker, maybe_padding] = self.map {
+            // Consume padding
+            while let &[.., maybe_marker, maybe_padding] = self.map {
                 if maybe_marker.offset() == self.offset + self.map.len() - 2 &&
                    maybe_padding.offset() == self.offset + self.map.len() - 1 &&
                    maybe_padding.is_padding()
@@ -333,36 +763,32 @@ impl<'a, H: Header> std::iter::DoubleEndedIterator for Blobs<'a, H> {
                 } else {
                     break
                 }
-            } else {
-                break
             }
-        };
 
-        if self.map.len() < 2 {
-            return None
-        }
+            if self.map.len() < 2 {
+                return None
+            }
 
-        let end_marker = self.map.last().expect("map not empty");
+            let end_marker = self.map.last().expect("map not empty");
 
-        let mut start_offset = self.map.len().checked_sub(2).expect("map not empty");
-        loop {
-            // Check if start_offset is the beginning of a valid blob
-            if self.map[start_offset].offset() == Offset::<H>::new(start_offset) {
-                // It is, so check if the blob itself is valid.
-                let blob = Marker::slice_to_bytes(&self.map[start_offset + 1 .. self.map.len() - 1]);
-                if let Some(blob_len) = blob.len().checked_sub(end_marker.padding_len()) {
-                    self.map = &self.map[0 .. start_offset + 1];
-                    let (blob, _padding) = blob.split_at(blob_len);
-                    return Some((Offset::new(start_offset), blob))
-                } else {
-                    unreachable!("we already consumed all padding")
-                }
+            let mut start_offset = self.map.len().checked_sub(2).expect("map not empty");
+
+            // Find the beginning of the blob. The first word of the map is always a marker, so
+            // this will terminate.
+            while self.map[start_offset].offset() != self.offset + start_offset {
+                start_offset -= 1;
             }
 
-            // Should be true, as we already consumed all the padding, and checked that there is at
-            // least one valid blob.
-            assert!(start_offset > 0);
-            start_offset -= 1;
+            let blob = Marker::slice_to_bytes(&self.map[start_offset + 1 .. self.map.len() - 1]);
+            self.map = &self.map[0 .. start_offset + 1];
+
+            if let Some(blob_len) = blob.len().checked_sub(end_marker.padding_len()) {
+                let (blob, _padding) = blob.split_at(blob_len);
+                return Some((self.offset + start_offset, blob))
+            } else {
+                // Invalid padding length. As in `next`, skip over it.
+                continue
+            }
         }
     }
 }
@@ -385,7 +811,7 @@ impl<H: Header> Breccia<H> {
     pub fn binary_search<F, R>(&self, f: F) -> Option<R>
         where F: FnMut(Offset<H>, &[u8]) -> Result<Option<R>, Search>
     {
-        let last_offset = Offset::new(self.map().len());
+        let last_offset = Offset::new(self.visible_map().len());
         self.binary_search_in_range(f, Offset::new(0) .. last_offset)
     }
 
@@ -409,20 +835,21 @@ impl<H: Header> Breccia<H> {
         }
 
         let midpoint = range.start.midpoint(range.end);
-        let mut blobs = Blobs::<H>::new(&self.map()[midpoint.raw as usize ..], midpoint);
+        let map = self.visible_map().get(midpoint.raw ..).unwrap_or(&[]);
+        let mut blobs = Blobs::<H>::new(map, midpoint);
 
         loop {
-            if let Some((offset, blob)) = blobs.next() {
-                if offset < range.end {
-                    match f(offset, blob) {
-                        Ok(Some(r)) => break Some(r),
-                        Ok(None) => break None,
-                        Err(Search::Next) => {
-                            continue
-                        },
-                        Err(Search::Right) => break self.binary_search_in_range(f, midpoint.offset(1) .. range.end),
-                        Err(Search::Left) => break self.binary_search_in_range(f, range.start .. midpoint),
-                    }
+            if let Some((offset, blob)) = blobs.next() &&
+               offset < range.end
+            {
+                match f(offset, blob) {
+                    Ok(Some(r)) => break Some(r),
+                    Ok(None) => break None,
+                    Err(Search::Next) => {
+                        continue
+                    },
+                    Err(Search::Right) => break self.binary_search_in_range(f, midpoint.offset(1) .. range.end),
+                    Err(Search::Left) => break self.binary_search_in_range(f, range.start .. midpoint),
                 }
             }
 
@@ -439,12 +866,73 @@ impl<H: Header> Breccia<H> {
     }
 }
 
+/// Returns `true` if the word at index `i` is a marker, and not a padding marker.
+///
+/// A dirty end marker with seven bytes of padding is indistinguishable from a padding marker on
+/// its own. But padding markers always immediately follow another marker, while end markers with
+/// padding always follow at least one word of blob data.
+fn is_end_marker<H>(markers: &[Marker], i: usize) -> bool {
+    let is_marker = |i: usize| markers[i].offset::<H>().raw == i;
+
+    is_marker(i) && !(markers[i].is_padding() && i > 0 && is_marker(i - 1))
+}
+
+/// Determines how many padding markers are needed to write a blob after the marker at
+/// `blob_offset` without any of its words colliding with a marker.
+fn padding_needed<H: Header>(blob_offset: Offset<H>, blob: &[u8]) -> usize {
+    let mut padding = 0;
+    'outer: loop {
+        // Note that the last chunk can't actually collide except for truly enormous files.
+        // FIXME: should we use 0 padding so we can actually test this?
+        let (chunks, tail) = blob.as_chunks::<{size_of::<Marker>()}>();
+        let last_chunk = if !tail.is_empty() {
+            let mut b = [0xfe; size_of::<Marker>()];
+            b[0 .. tail.len()].copy_from_slice(tail);
+            Some(b)
+        } else {
+            None
+        };
+
+        let chunks = chunks.iter().chain(last_chunk.as_ref());
+        for (i, chunk) in chunks.enumerate() {
+            let possible_marker = Marker::from(chunk);
+            if blob_offset.offset(1).offset(padding).offset(i) == possible_marker.offset() {
+                padding += 1;
+                continue 'outer
+            }
+        }
+        break padding
+    }
+}
+
+fn uncommitted_tail_error() -> io::Error {
+    io::Error::other("breccia ends with uncommitted data; use BrecciaMut::recover")
+}
+
 /// Batch writing.
+///
+/// Blobs written to a batch only become visible once the batch is committed. Dropping a batch
+/// without committing it rolls back everything written to it.
+///
+/// # Rollback and concurrent readers
+///
+/// Rolling back a batch truncates the file, which can crash readers of it with `SIGBUS`. A reader
+/// that maps the file while it holds the rolled back blobs reads the last words of the map to
+/// find its tail, and if the file is truncated in between, those words no longer exist. Blobs
+/// reach the file whenever the batch's buffer fills, not just when it's committed, so avoid
+/// rolling back batches while other threads or processes may be reloading the file, such as
+/// with `Breccia::follow`.
 #[derive(Debug)]
 pub struct Batch<'a, H> {
     target: &'a mut BrecciaMut<H>,
     blob_offset: Offset<H>,
-    fd: BufWriter<File>,
+
+    /// `None` once the batch has been committed or rolled back.
+    fd: Option<BufWriter<File>>,
+
+    /// The length of the file when the batch was started.
+    start_len: u64,
+
     pending_marker: Option<Marker>,
 }
 
@@ -453,70 +941,53 @@ impl<'a, H: Header> Batch<'a, H> {
         let mut fd = target.fd.try_clone()?;
 
         let blob_offset = fd.seek(SeekFrom::End(-(size_of::<Marker>() as i64)))?;
-        let blob_offset = Offset::<H>::try_from_file_offset(blob_offset as u64)
-                                      .expect("TODO: handle unclean file");
+        let blob_offset = Offset::<H>::try_from_file_offset(blob_offset)
+                                      .map_err(|_| uncommitted_tail_error())?;
 
         let mut buf = [0u8; size_of::<Marker>()];
         fd.read_exact(&mut buf)?;
 
+        // The last word must be a clean end marker; anything else is left over from a batch that
+        // was never committed.
         let end_marker = Marker::from(buf);
-        if end_marker.state() == Dirty {
-            todo!("handle dirty file")
+        if end_marker.offset() != blob_offset || end_marker.state() == Dirty {
+            return Err(uncommitted_tail_error());
         }
 
         Ok(Self {
             target,
             blob_offset,
-            fd: BufWriter::new(fd),
+            fd: Some(BufWriter::new(fd)),
+            start_len: blob_offset.to_file_offset() + size_of::<Marker>() as u64,
             pending_marker: None,
         })
     }
 
+    fn fd(&mut self) -> &mut BufWriter<File> {
+        self.fd.as_mut().expect("batch not yet committed or rolled back")
+    }
+
     /// Writes a blob.
     ///
     /// Returns the `Offset` of the newly-written blob.
     pub fn write_blob(&mut self, blob: &[u8]) -> io::Result<Offset<H>> {
         if let Some(pending_marker) = self.pending_marker.take() {
-            self.fd.write_all(&pending_marker.to_bytes())?;
-        }
-
-        // determine how much padding we need
-        let mut padding = 0;
-        'outer: loop {
-            // Note that the last chunk can't actually collide except for truly enormous files.
-            // FIXME: should we use 0 padding so we can actually test this?
-            let (chunks, tail) = blob.as_chunks::<{size_of::<Marker>()}>();
-            let last_chunk = if tail.len() > 0 {
-                let mut b = [0xfe; size_of::<Marker>()];
-                (&mut b[0 .. tail.len()]).copy_from_slice(tail);
-                Some(b)
-            } else {
-                None
-            };
-
-            let chunks = chunks.into_iter().chain(last_chunk.as_ref());
-            for (i, chunk) in chunks.enumerate() {
-                let possible_marker = Marker::from(chunk);
-                if self.blob_offset.offset(1).offset(padding).offset(i) == possible_marker.offset() {
-                    padding += 1;
-                    continue 'outer
-                }
-            }
-            break
+            self.fd().write_all(&pending_marker.to_bytes())?;
         }
 
+        let padding = padding_needed(self.blob_offset, blob);
         for i in 0 .. padding {
             let pad_offset = self.blob_offset.offset(1 + i);
             let marker = Marker::new_padding(pad_offset);
-            self.fd.write(&marker.to_bytes())?;
+            self.fd().write_all(&marker.to_bytes())?;
         }
         let blob_offset = self.blob_offset.offset(padding);
 
-        self.fd.write(blob)?;
+        self.fd().write_all(blob)?;
 
         let end_padding_len = blob.len().next_multiple_of(size_of::<Marker>()) - blob.len();
         let end_padding = &[0xfe; size_of::<Marker>() - 1][0 .. end_padding_len];
-        self.fd.write(end_padding)?;
+        self.fd().write_all(end_padding)?;
 
         let end_marker_offset = blob_offset.offset(1 + ((blob.len() + end_padding.len()) / size_of::<Marker>()));
         self.pending_marker = Some(Marker::new(end_marker_offset, end_padding.len(), Dirty));
@@ -526,26 +997,78 @@ impl<'a, H: Header> Batch<'a, H> {
     }
 
     /// Commits this batch of blobs.
-    pub fn commit(mut self) -> io::Result<()> {
+    ///
+    /// Committing an empty batch does nothing.
+    ///
+    /// If writing or syncing the clean end marker fails, the batch is rolled back. Once it has
+    /// been synced the batch is committed, so if reloading the breccia then fails, the error is
+    /// returned but nothing is rolled back; the blobs become visible on the next `reload`.
+    pub fn commit(self) -> io::Result<()> {
+        self.commit_with(true)
+    }
+
+    /// Commits this batch without syncing it to disk, for files that needn't survive a crash.
+    pub(crate) fn commit_unsynced(self) -> io::Result<()> {
+        self.commit_with(false)
+    }
+
+    fn commit_with(mut self, sync: bool) -> io::Result<()> {
         if let Some(mut pending_marker) = self.pending_marker.take() {
             pending_marker.set_state(Clean);
-            self.fd.write_all(&pending_marker.to_bytes())?;
-        } else {
-            panic!("no blobs written");
+
+            let fd = self.fd();
+            fd.write_all(&pending_marker.to_bytes())?;
+            fd.flush()?;
+            if sync {
+                fd.get_mut().sync_all()?;
+            }
+
+            // Committed, so there's nothing left to roll back.
+            self.fd = None;
+            self.target.reload()?;
         }
+        Ok(())
+    }
 
-        self.fd.flush()?;
-        self.fd.get_mut().sync_all()?;
-        self.target.reload()?;
+    /// Aborts this batch, discarding all blobs written to it.
+    ///
+    /// This is what dropping an uncommitted batch does, except that errors are returned rather
+    /// than ignored.
+    ///
+    /// The file is truncated, which can crash concurrent readers; see the `Batch` documentation.
+    pub fn abort(mut self) -> io::Result<()> {
+        self.rollback()
+    }
+}
+
+impl<H> Batch<'_, H> {
+    /// Truncates the file back to its length when the batch was started.
+    ///
+    /// The target's map was never reloaded to include the rolled back blobs, so it doesn't need
+    /// to be reloaded now.
+    fn rollback(&mut self) -> io::Result<()> {
+        if let Some(fd) = self.fd.take() {
+            // Anything still buffered is discarded, rather than written out.
+            let (fd, _buffered) = fd.into_parts();
+            fd.set_len(self.start_len)?;
+        }
         Ok(())
     }
 }
 
+impl<H> Drop for Batch<'_, H> {
+    fn drop(&mut self) {
+        let _ = self.rollback();
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)] // groupings follow the marker bit fields
 mod tests {
     use tempfile::tempfile;
 
     use super::*;
+    use crate::test_util::{BigHeader, DATA};
 
     #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
     struct TestHeader(u8);
@@ -564,6 +1087,11 @@ mod tests {
         }
     }
 
+    /// Creates a breccia directly from marker words, to test corrupt or partially written files.
+    fn breccia_from_markers(markers: &[Marker]) -> io::Result<Breccia<TestHeader>> {
+        test_util::breccia_from_markers(TestHeader(0x42), markers)
+    }
+
     #[test]
     fn create() -> io::Result<()> {
         let breccia = BrecciaMut::create_from_file(tempfile()?, TestHeader(0x42))?;
@@ -646,6 +1174,93 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn blobs_from_before_and_in() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, TestHeader(0x42))?;
+
+        let mut batch = b.start_batch()?;
+        let blobs = [&b"zero"[..], &[], &[4,0,0,0,0,0,0,0b1110_0000], b"three", &[0; 20], b"five"];
+        let offsets = blobs.iter()
+                           .map(|blob| batch.write_blob(blob))
+                           .collect::<io::Result<Vec<_>>>()?;
+        batch.commit()?;
+        let expected = offsets.iter().copied().zip(blobs).collect::<Vec<_>>();
+
+        // The colliding blob needs padding, so it doesn't start at the previous blob's end marker.
+        assert_eq!(offsets[2], offsets[1] + 2);
+
+        for (i, offset) in offsets.iter().enumerate() {
+            assert_eq!(b.blobs_from(*offset).collect::<Vec<_>>(), &expected[i ..]);
+            assert_eq!(b.blobs_from(*offset).rev().collect::<Vec<_>>(),
+                       expected[i ..].iter().copied().rev().collect::<Vec<_>>());
+
+            assert_eq!(b.blobs_before(*offset).collect::<Vec<_>>(), &expected[.. i]);
+            assert_eq!(b.blobs_before(*offset).rev().collect::<Vec<_>>(),
+                       expected[.. i].iter().copied().rev().collect::<Vec<_>>());
+
+            assert_eq!(b.blobs_from(*offset + 1).next(), expected.get(i + 1).copied());
+        }
+
+        // Offsets in the middle of a blob.
+        assert_eq!(b.blobs_from(offsets[4] + 1).next(), Some(expected[5]));
+        assert_eq!(b.blobs_before(offsets[4] + 1).next_back(), Some(expected[3]));
+
+        assert_eq!(b.blobs_in(offsets[1] .. offsets[4]).collect::<Vec<_>>(), &expected[1 .. 4]);
+        assert_eq!(b.blobs_in(offsets[1] .. offsets[4]).rev().collect::<Vec<_>>(),
+                   expected[1 .. 4].iter().copied().rev().collect::<Vec<_>>());
+        assert_eq!(b.blobs_in(offsets[1] .. offsets[1]).next(), None);
+        assert_eq!(b.blobs_from(Offset::new(1000)).next(), None);
+        assert_eq!(b.blobs_before(Offset::new(1000)).count(), blobs.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn blobs_at_file_offsets() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, TestHeader(0x42))?;
+
+        assert_eq!(b.next_blob_at_or_after(0), None);
+        assert_eq!(b.prev_blob_before(u64::MAX), None);
+
+        let mut batch = b.start_batch()?;
+        let first = batch.write_blob(b"first blob")?;
+        let second = batch.write_blob(&[4,0,0,0,0,0,0,0b1110_0000])?;
+        let third = batch.write_blob(b"third")?;
+        batch.commit()?;
+
+        let first = (first, &b"first blob"[..]);
+        let second = (second, &[4,0,0,0,0,0,0,0b1110_0000][..]);
+        let third = (third, &b"third"[..]);
+
+        // Within the header.
+        for file_offset in 0 .. 8 {
+            assert_eq!(b.next_blob_at_or_after(file_offset), Some(first));
+            assert_eq!(b.prev_blob_before(file_offset), None);
+        }
+        assert_eq!(b.next_blob_at_or_after(8), Some(first));
+        assert_eq!(b.prev_blob_before(8), None);
+
+        // Within the first blob.
+        for file_offset in 9 .. 32 {
+            assert_eq!(b.next_blob_at_or_after(file_offset), Some(second));
+            assert_eq!(b.prev_blob_before(file_offset), Some(first));
+        }
+
+        // The second blob's start marker, and padding.
+        assert_eq!(b.next_blob_at_or_after(32), Some(second));
+        assert_eq!(b.next_blob_at_or_after(40), Some(second));
+        assert_eq!(b.prev_blob_before(40), Some(first));
+        assert_eq!(b.prev_blob_before(41), Some(second));
+        assert_eq!(b.next_blob_at_or_after(41), Some(third));
+
+        assert_eq!(b.next_blob_at_or_after(b.committed_len() - 8), None);
+        assert_eq!(b.prev_blob_before(b.committed_len()), Some(third));
+        assert_eq!(b.next_blob_at_or_after(u64::MAX), None);
+        assert_eq!(b.prev_blob_before(u64::MAX), Some(third));
+
+        Ok(())
+    }
+
     #[test]
     fn write_colliding_blob() -> io::Result<()> {
         let mut b = BrecciaMut::create_from_file(tempfile()?, TestHeader(0x42))?;
@@ -669,6 +1284,370 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn create_truncates_existing_file() -> io::Result<()> {
+        let path = tempfile::NamedTempFile::new()?.into_temp_path();
+        std::fs::write(&path, [0xff; 1000])?;
+
+        let b = BrecciaMut::create(&path, TestHeader(0x42))?;
+        assert_eq!(b.blobs().count(), 0);
+        assert_eq!(std::fs::metadata(&path)?.len(), b.committed_len());
+        Ok(())
+    }
+
+    #[test]
+    fn recover_dirty_tail() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, TestHeader(0x42))?;
+
+        assert!(b.recover()?.is_clean());
+
+        let committed = b.write_blob(b"committed")?;
+
+        // Simulate a crash part-way through a batch.
+        let mut batch = b.start_batch()?;
+        let lost = batch.write_blob(b"lost")?;
+        batch.write_blob(b"lost too")?;
+        batch.fd().flush()?;
+        std::mem::forget(batch);
+        b.reload()?;
+
+        assert!(b.start_batch().is_err());
+
+        let recovery = b.recover()?;
+        assert_eq!(recovery.committed, Offset::new(3));
+        assert_eq!(recovery.lost_blobs, vec![lost]);
+        assert_eq!(recovery.lost_bytes, 8 + 8 + 8);
+
+        let offset = b.write_blob(b"after recovery")?;
+        assert_eq!(offset, Offset::new(3));
+
+        let mut blobs = b.blobs();
+        assert_eq!(blobs.next(), Some((committed, &b"committed"[..])));
+        assert_eq!(blobs.next(), Some((offset, &b"after recovery"[..])));
+        assert_eq!(blobs.next(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn repair_by_committing() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, TestHeader(0x42))?;
+        let committed = b.write_blob(b"committed")?;
+
+        // A torn batch can't be committed, as that would keep only part of it.
+        let mut batch = b.start_batch()?;
+        batch.write_blob(b"torn")?;
+        batch.write_blob(b"torn too")?;
+        batch.fd().flush()?;
+        std::mem::forget(batch);
+
+        let file_len = b.fd.metadata()?.len();
+        assert!(b.repair(RepairMode::Commit).is_err());
+        assert_eq!(b.fd.metadata()?.len(), file_len);
+        assert_eq!(b.tail(), Tail::Torn);
+        assert_eq!(b.recover()?.lost_blobs.len(), 1);
+
+        // A batch that's complete apart from the commit is kept in full.
+        let mut batch = b.start_batch()?;
+        let kept = batch.write_blob(b"kept")?;
+        let kept_too = batch.write_blob(b"kept too")?;
+        let end_marker = batch.pending_marker.take().unwrap();
+        batch.fd().write_all(&end_marker.to_bytes())?;
+        batch.fd().flush()?;
+        std::mem::forget(batch);
+
+        let recovery = b.repair(RepairMode::Commit)?;
+        assert_eq!(recovery.committed, Offset::new(7));
+        assert_eq!(recovery.lost_blobs, vec![]);
+        assert_eq!(recovery.lost_bytes, 0);
+        assert_eq!(b.tail(), Tail::Clean);
+        assert!(b.verify().is_ok());
+
+        assert!(b.repair(RepairMode::Commit)?.is_clean());
+
+        let mut blobs = b.blobs();
+        assert_eq!(blobs.next(), Some((committed, &b"committed"[..])));
+        assert_eq!(blobs.next(), Some((kept, &b"kept"[..])));
+        assert_eq!(blobs.next(), Some((kept_too, &b"kept too"[..])));
+        assert_eq!(blobs.next(), None);
+
+        b.write_blob(b"after repair")?;
+        assert_eq!(b.blobs().count(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tail_state() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, TestHeader(0x42))?;
+        assert_eq!(b.tail(), Tail::Clean);
+        assert_eq!(b.committed(), Some(Offset::new(0)));
+
+        b.write_blob(b"committed")?;
+        assert_eq!(b.tail(), Tail::Clean);
+        assert_eq!(b.committed(), Some(Offset::new(3)));
+        assert_eq!(b.committed_len(), 8 + (4 * 8));
+
+        let mut batch = b.start_batch()?;
+        batch.write_blob(b"lost")?;
+        batch.write_blob(b"lost too")?;
+        batch.fd().flush()?;
+        std::mem::forget(batch);
+
+        b.reload()?;
+        assert_eq!(b.tail(), Tail::Torn);
+        assert_eq!(b.committed(), Some(Offset::new(3)));
+
+        let len = b.fd.metadata()?.len();
+        b.fd.set_len(len - 8)?;
+        b.reload()?;
+        assert_eq!(b.tail(), Tail::Dirty);
+        assert_eq!(b.committed(), Some(Offset::new(3)));
+
+        b.fd.set_len(len - 5)?;
+        b.reload()?;
+        assert_eq!(b.tail(), Tail::Unaligned);
+        assert_eq!(b.committed(), Some(Offset::new(3)));
+        assert_eq!(b.committed_len(), 8 + (4 * 8));
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_blob_errors() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, TestHeader(0x42))?;
+        b.write_blob(&[1,0,0,0,0,0,0,0b1110_0000])?;
+
+        assert_eq!(b.get_blob(Offset::new(0)), Err(GetBlobError::PaddingInBlob));
+        assert_eq!(b.get_blob(Offset::new(1)).map(|blob| blob.as_bytes()), Ok(&[1,0,0,0,0,0,0,0b1110_0000][..]));
+        assert_eq!(b.get_blob(Offset::new(2)), Err(GetBlobError::Unaligned));
+        assert_eq!(b.get_blob(Offset::new(3)), Err(GetBlobError::OutOfRange));
+        assert_eq!(b.get_blob(Offset::new(4)), Err(GetBlobError::OutOfRange));
+
+        let b = breccia_from_markers(&[Marker(0), Marker::new(Offset::<TestHeader>::new(1), 3, Clean)])?;
+        assert_eq!(b.get_blob(Offset::new(0)), Err(GetBlobError::BadPaddingLength));
+        assert_eq!(b.blobs().next(), None);
+        assert_eq!(b.blobs().next_back(), None);
+
+        let mut b = breccia_from_markers(&[Marker(0), DATA])?;
+        assert_eq!(b.get_blob(Offset::new(0)), Err(GetBlobError::OutOfRange));
+        b.set_read_uncommitted(true);
+        assert_eq!(b.get_blob(Offset::new(0)), Err(GetBlobError::Unterminated));
+        assert_eq!(b.blobs().next(), None);
+        assert_eq!(b.blobs().next_back(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn blobs_reversed_with_torn_tail() -> io::Result<()> {
+        let b = breccia_from_markers(&[Marker(0), DATA, Marker::new(Offset::<TestHeader>::new(2), 7, Clean), DATA])?;
+        assert_eq!(b.tail(), Tail::Torn);
+
+        let mut blobs = b.blobs();
+        assert_eq!(blobs.next_back(), Some((Offset::new(0), &[0x2a][..])));
+        assert_eq!(blobs.next_back(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn get_blob_from_many_threads() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, TestHeader(0x42))?;
+
+        let mut batch = b.start_batch()?;
+        let offsets = (0 .. 100u32).map(|i| batch.write_blob(&i.to_le_bytes()))
+                                   .collect::<io::Result<Vec<_>>>()?;
+        batch.commit()?;
+
+        let b = std::sync::Arc::new(Breccia::<TestHeader>::open_file(b.fd.try_clone()?)?);
+        std::thread::scope(|scope| {
+            for _ in 0 .. 4 {
+                let b = std::sync::Arc::clone(&b);
+                let offsets = &offsets;
+                scope.spawn(move || {
+                    for (i, offset) in offsets.iter().enumerate() {
+                        let blob = b.get_blob(*offset).unwrap();
+                        assert_eq!(blob.offset(), *offset);
+                        assert_eq!(blob.len(), 4);
+                        assert_eq!(&blob[..], &(i as u32).to_le_bytes());
+                    }
+                });
+            }
+        });
+
+        Ok(())
+    }
+
+    #[test]
+    fn blobs_outlive_reload() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, TestHeader(0x42))?;
+        let offset = b.write_blob(b"owned blob")?;
+
+        let blob = b.get_blob(offset).unwrap().to_blob();
+        b.write_blob(b"another blob")?;
+        b.reload()?;
+
+        let blob = std::thread::spawn(move || {
+            assert_eq!(blob.offset(), offset);
+            assert_eq!(&blob[..], b"owned blob");
+            blob
+        }).join().unwrap();
+        drop(b);
+
+        assert_eq!(blob.as_bytes(), b"owned blob");
+        assert_eq!(blob.clone(), blob);
+
+        #[cfg(feature = "bytes")]
+        assert_eq!(bytes::Bytes::from(blob), &b"owned blob"[..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn blob_at() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, TestHeader(0x42))?;
+        let mut batch = b.start_batch()?;
+        batch.write_blob(b"first")?;
+        batch.write_blob(&[])?;
+        batch.write_blob(&[2,0,0,0,0,0,0,0b1110_0000])?;
+        batch.commit()?;
+
+        let blobs: Vec<_> = b.blobs().map(|(offset, bytes)| (offset, b.blob_at(bytes))).collect();
+        b.reload()?;
+        assert_eq!(blobs.len(), 3);
+        for (offset, blob) in &blobs {
+            assert_eq!(blob.offset(), *offset);
+            assert_eq!(blob.as_bytes(), b.get_blob(*offset).unwrap().as_bytes());
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic(expected = "blob is not in this breccia's map")]
+    fn blob_at_outside_map() {
+        let b = BrecciaMut::create_from_file(tempfile().unwrap(), TestHeader(0x42)).unwrap();
+        b.blob_at(b"elsewhere");
+    }
+
+    #[test]
+    #[should_panic(expected = "blob is not in this breccia's map")]
+    fn blob_at_part_of_blob() {
+        let mut b = BrecciaMut::create_from_file(tempfile().unwrap(), TestHeader(0x42)).unwrap();
+        let offset = b.write_blob(b"a blob").unwrap();
+        b.blob_at(&b.get_blob(offset).unwrap()[1 ..]);
+    }
+
+    #[test]
+    fn readers_only_see_committed_blobs() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, TestHeader(0x42))?;
+        let committed = b.write_blob(&0u32.to_le_bytes())?;
+        let mut reader = Breccia::<TestHeader>::open_file(b.fd.try_clone()?)?;
+
+        let mut batch = b.start_batch()?;
+        let uncommitted = batch.write_blob(&1u32.to_le_bytes())?;
+        batch.write_blob(&2u32.to_le_bytes())?;
+        batch.fd().flush()?;
+
+        reader.reload()?;
+        assert_eq!(reader.tail(), Tail::Torn);
+
+        assert_eq!(reader.blobs().collect::<Vec<_>>(),
+                   vec![(committed, &0u32.to_le_bytes()[..])]);
+        assert_eq!(reader.get_blob(uncommitted), Err(GetBlobError::OutOfRange));
+
+        let search_for = |b: &Breccia<TestHeader>, n: u32| b.binary_search(|offset, blob| {
+            let found = u32::from_le_bytes(blob.try_into().unwrap());
+            match n.cmp(&found) {
+                std::cmp::Ordering::Equal => Ok(Some(offset)),
+                std::cmp::Ordering::Less => Err(Search::Left),
+                std::cmp::Ordering::Greater => Err(Search::Right),
+            }
+        });
+        assert_eq!(search_for(&reader, 0), Some(committed));
+        assert_eq!(search_for(&reader, 1), None);
+
+        reader.set_read_uncommitted(true);
+        assert_eq!(reader.blobs().collect::<Vec<_>>(),
+                   vec![(committed, &0u32.to_le_bytes()[..]),
+                        (uncommitted, &1u32.to_le_bytes()[..])]);
+        assert_eq!(reader.get_blob(uncommitted).map(|blob| blob.as_bytes()), Ok(&1u32.to_le_bytes()[..]));
+        assert_eq!(search_for(&reader, 1), Some(uncommitted));
+
+        reader.set_read_uncommitted(false);
+        batch.commit()?;
+        reader.reload()?;
+        assert_eq!(reader.blobs().count(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reload_summary() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, TestHeader(0x42))?;
+        let mut reader = Breccia::<TestHeader>::open_file(b.fd.try_clone()?)?;
+
+        let summary = reader.reload()?;
+        assert!(!summary.has_new_blobs());
+        assert_eq!(summary.old_end(), Offset::new(0));
+        assert_eq!(summary.new_end(), Offset::new(0));
+        assert_eq!(summary.new_blob_count(), 0);
+
+        let first = b.write_blob(b"first")?;
+        let mut batch = b.start_batch()?;
+        let second = batch.write_blob(&[])?;
+        batch.write_blob(&[0; 100])?;
+        batch.fd().flush()?;
+
+        // Only the committed blob is new.
+        let summary = reader.reload()?;
+        assert!(summary.has_new_blobs());
+        assert_eq!(summary.old_end(), Offset::new(0));
+        assert_eq!(summary.new_end(), second);
+        assert_eq!(summary.new_blobs().collect::<Vec<_>>(), vec![(first, &b"first"[..])]);
+        assert_eq!(summary.new_blob_count(), 1);
+
+        batch.commit()?;
+        let summary = reader.reload()?;
+        assert_eq!(summary.old_end(), second);
+        assert_eq!(summary.new_end(), b.committed().unwrap());
+        assert_eq!(summary.new_blobs().map(|(_, blob)| blob.len()).collect::<Vec<_>>(), vec![0, 100]);
+        assert_eq!(summary.new_blob_count(), 2);
+
+        assert!(!reader.reload()?.has_new_blobs());
+
+        Ok(())
+    }
+
+    #[test]
+    fn batch_abort_and_drop() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, TestHeader(0x42))?;
+        let committed = b.write_blob(b"committed")?;
+        let len = b.fd.metadata()?.len();
+
+        b.start_batch()?.commit()?;
+        assert_eq!(b.fd.metadata()?.len(), len);
+
+        let mut batch = b.start_batch()?;
+        batch.write_blob(b"aborted")?;
+        batch.write_blob(&[0; 10_000])?; // bigger than the write buffer
+        batch.abort()?;
+        assert_eq!(b.fd.metadata()?.len(), len);
+
+        {
+            let mut batch = b.start_batch()?;
+            batch.write_blob(b"dropped")?;
+            batch.write_blob(&[0; 10_000])?;
+        }
+        assert_eq!(b.fd.metadata()?.len(), len);
+
+        let offset = b.write_blob(b"written")?;
+        assert_eq!(b.blobs().collect::<Vec<_>>(),
+                   vec![(committed, &b"committed"[..]), (offset, &b"written"[..])]);
+        assert_eq!(b.tail(), Tail::Clean);
+
+        Ok(())
+    }
+
     #[test]
     fn binary_search_on_empty_blobs() -> io::Result<()> {
         let mut breccia = BrecciaMut::create_from_file(tempfile()?, TestHeader(0x42))?;
@@ -780,29 +1759,56 @@ mod tests {
         Ok(())
     }
 
-    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
-    struct BigHeader(u8);
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct SignedHeader(i8);
 
-    impl Header for BigHeader {
-        const MAGIC: &[u8] = b"\x00\x01\x02\x03";
+    impl Header for SignedHeader {
+        const MAGIC: &[u8] = b"\x00";
         const SERIALIZED_SIZE: usize = 1;
 
         fn serialize(&self, dst: &mut [u8]) {
-            dst[0] = self.0;
+            dst[0] = self.0 as u8;
         }
 
-        type DeserializeError = std::convert::Infallible;
+        type DeserializeError = std::num::TryFromIntError;
         fn deserialize(src: &[u8]) -> Result<Self, Self::DeserializeError> {
-            Ok(Self(src[0]))
+            Ok(Self(i8::try_from(src[0])?))
         }
     }
 
+    fn open_bytes<H: Header>(bytes: &[u8]) -> Result<Breccia<H>, Error<H::DeserializeError>> {
+        let mut fd = tempfile()?;
+        fd.write_all(bytes)?;
+        Breccia::open_file(fd)
+    }
+
+    #[test]
+    fn open_errors() {
+        assert!(open_bytes::<SignedHeader>(b"\x00\x01\x00\x00\x00\x00\x00\x00\
+                                             \x00\x00\x00\x00\x00\x00\x00\x00").is_ok());
+
+        assert!(matches!(open_bytes::<SignedHeader>(b"\x01\x01\x00\x00\x00\x00\x00\x00"),
+                         Err(Error::BadMagic)));
+        assert!(matches!(open_bytes::<SignedHeader>(b"\x00\x80\x00\x00\x00\x00\x00\x00"),
+                         Err(Error::Header(_))));
+        assert!(matches!(open_bytes::<SignedHeader>(b"\x00\x01\x00\x00\x00\x00\x01\x00"),
+                         Err(Error::NonZeroPadding)));
+        assert!(matches!(open_bytes::<SignedHeader>(b""),
+                         Err(Error::TruncatedHeader)));
+        assert!(matches!(open_bytes::<SignedHeader>(b"\x00\x01\x00\x00"),
+                         Err(Error::TruncatedHeader)));
+
+        let err = io::Error::from(open_bytes::<SignedHeader>(b"\x01").unwrap_err());
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
     #[test]
     fn create_big_header() -> io::Result<()> {
         let breccia = BrecciaMut::create_from_file(tempfile()?, BigHeader(0x42))?;
 
         assert_eq!(&breccia.map[..],
-                   b"\x00\x01\x02\x03\x42\x00\x00\x00\
+                   b"\x00\x01\x02\x03\x04\x05\x06\x07\
+                     \x08\x42\x00\x00\x00\x00\x00\x00\
                      \x00\x00\x00\x00\x00\x00\x00\x00");
         assert_eq!(&breccia.map(),
                    &[Marker(0)]);
@@ -15,7 +15,7 @@ impl Marker {
     /// The offset, in bits, that the padding length is encoded in.
     const PADDING_LEN_OFFSET: u32 = usize::BITS - 3;
 
-    const STATE_BIT_OFFSET: u32 = usize::BITS - 4;
+    pub(crate) const STATE_BIT_OFFSET: u32 = usize::BITS - 4;
 
     /// Creates a new `Marker` from an `Offset` and a padding length.
     pub const fn new<H>(offset: Offset<H>, padding_len: usize, dirty: State) -> Self {
@@ -30,11 +30,7 @@ impl Marker {
     }
 
     pub fn is_padding(&self) -> bool {
-        if self.padding_len() == 7 && self.state() == Dirty {
-            true
-        } else {
-            false
-        }
+        self.padding_len() == 7 && self.state() == Dirty
     }
 
     /// Returns the `Offset` this `Marker` represents.
@@ -70,7 +66,7 @@ impl Marker {
         unsafe {
             std::slice::from_raw_parts(
                 slice.as_ptr() as *const u8,
-                slice.len() * size_of::<Marker>()
+                size_of_val(slice)
             )
         }
     }
@@ -89,6 +85,7 @@ impl From<&[u8; size_of::<Self>()]> for Marker {
 }
 
 #[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)] // groupings follow the marker bit fields
 mod tests {
     use super::*;
 
@@ -22,9 +22,19 @@ pub enum TryFromFileOffsetError {
     /// The offset was not aligned to a marker.
     #[error("file offset unaligned")]
     Unaligned,
+
+    /// The offset was larger than `Offset::MAX`.
+    #[error("file offset too large")]
+    TooLarge,
 }
 
 impl<H> Offset<H> {
+    /// The offset of the first marker.
+    pub const ZERO: Self = Self::new(0);
+
+    /// The largest offset that can be represented by a marker.
+    pub const MAX: Self = Self::new((1 << Marker::STATE_BIT_OFFSET) - 1);
+
     /// Creates a new offset.
     ///
     /// The value is *not* a file offset.
@@ -34,10 +44,48 @@ impl<H> Offset<H> {
             _marker: PhantomData,
         }
     }
+
+    /// Converts this offset to an integer, e.g. for storage in an external index.
+    ///
+    /// The value is *not* a file offset; use `to_file_offset` for that.
+    pub const fn to_u64(self) -> u64 {
+        self.raw as u64
+    }
+
+    /// Converts an integer previously returned by `to_u64` back into an offset.
+    ///
+    /// Returns `None` if the integer is larger than `Offset::MAX`.
+    pub const fn from_u64(raw: u64) -> Option<Self> {
+        if raw <= Self::MAX.raw as u64 {
+            Some(Self::new(raw as usize))
+        } else {
+            None
+        }
+    }
+
+    /// Adds `n` words to this offset, returning `None` if the result is larger than
+    /// `Offset::MAX`.
+    pub const fn checked_add(self, n: usize) -> Option<Self> {
+        match self.raw.checked_add(n) {
+            Some(raw) if raw <= Self::MAX.raw => Some(Self::new(raw)),
+            _ => None,
+        }
+    }
+
+    /// Subtracts `n` words from this offset, returning `None` on underflow.
+    pub const fn checked_sub(self, n: usize) -> Option<Self> {
+        match self.raw.checked_sub(n) {
+            Some(raw) => Some(Self::new(raw)),
+            None => None,
+        }
+    }
 }
 
 impl<H: Header> Offset<H> {
-    pub(crate) fn try_from_file_offset(file_offset: u64) -> Result<Self, TryFromFileOffsetError> {
+    /// Converts a position within the file to an offset.
+    ///
+    /// The position must be the start of a word after the header.
+    pub fn try_from_file_offset(file_offset: u64) -> Result<Self, TryFromFileOffsetError> {
         let file_offset = usize::try_from(file_offset).expect("u64 to usize conversion should be lossless");
 
         let offset = file_offset.checked_sub(H::SIZE_WITH_PADDING)
@@ -47,7 +95,13 @@ impl<H: Header> Offset<H> {
             return Err(TryFromFileOffsetError::Unaligned);
         }
 
-        Ok(Self::new(offset / size_of::<Marker>()))
+        Self::from_u64((offset / size_of::<Marker>()) as u64)
+             .ok_or(TryFromFileOffsetError::TooLarge)
+    }
+
+    /// Returns the position of this offset's marker within the file.
+    pub fn to_file_offset(self) -> u64 {
+        (H::SIZE_WITH_PADDING + (self.raw * size_of::<Marker>())) as u64
     }
 
     pub(crate) fn offset(self, n: usize) -> Self {
@@ -87,7 +141,7 @@ impl<H> cmp::Eq for Offset<H> {
 
 impl<H> cmp::PartialOrd for Offset<H> {
     fn partial_cmp(&self, rhs: &Self) -> Option<cmp::Ordering> {
-        Some(self.raw.cmp(&rhs.raw))
+        Some(self.cmp(rhs))
     }
 }
 
@@ -105,8 +159,7 @@ impl<H> hash::Hash for Offset<H> {
 
 impl<H> ops::AddAssign<usize> for Offset<H> {
     fn add_assign(&mut self, rhs: usize) {
-        // TODO: check for overflow
-        self.raw += rhs;
+        *self = *self + rhs;
     }
 }
 
@@ -114,7 +167,7 @@ impl<H> ops::Add<usize> for Offset<H> {
     type Output = Self;
 
     fn add(self, rhs: usize) -> Self {
-        Offset::new(self.raw + rhs)
+        self.checked_add(rhs).expect("offset overflow")
     }
 }
 
@@ -122,6 +175,80 @@ impl<H> ops::Sub<usize> for Offset<H> {
     type Output = Self;
 
     fn sub(self, rhs: usize) -> Self {
-        Offset::new(self.raw - rhs)
+        self.checked_sub(rhs).expect("offset underflow")
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<H> serde::Serialize for Offset<H> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.to_u64())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, H> serde::Deserialize<'de> for Offset<H> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = u64::deserialize(deserializer)?;
+        Self::from_u64(raw).ok_or_else(|| serde::de::Error::custom("offset too large"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::BigHeader;
+
+    #[test]
+    fn u64_conversions() {
+        assert_eq!(Offset::<()>::ZERO.to_u64(), 0);
+        assert_eq!(Offset::<()>::from_u64(42), Some(Offset::new(42)));
+        assert_eq!(Offset::<()>::from_u64(Offset::<()>::MAX.to_u64()), Some(Offset::MAX));
+        assert_eq!(Offset::<()>::from_u64(Offset::<()>::MAX.to_u64() + 1), None);
+        assert_eq!(Offset::<()>::from_u64(u64::MAX), None);
+    }
+
+    #[test]
+    fn file_offset_conversions() {
+        assert_eq!(Offset::<BigHeader>::ZERO.to_file_offset(), 16);
+        assert_eq!(Offset::<BigHeader>::new(3).to_file_offset(), 40);
+
+        assert_eq!(Offset::<BigHeader>::try_from_file_offset(0), Err(TryFromFileOffsetError::WithinHeader));
+        assert_eq!(Offset::<BigHeader>::try_from_file_offset(15), Err(TryFromFileOffsetError::WithinHeader));
+        assert_eq!(Offset::<BigHeader>::try_from_file_offset(16), Ok(Offset::ZERO));
+        assert_eq!(Offset::<BigHeader>::try_from_file_offset(17), Err(TryFromFileOffsetError::Unaligned));
+        assert_eq!(Offset::<BigHeader>::try_from_file_offset(40), Ok(Offset::new(3)));
+        assert_eq!(Offset::<BigHeader>::try_from_file_offset(Offset::<BigHeader>::MAX.to_file_offset()),
+                   Ok(Offset::MAX));
+        assert_eq!(Offset::<BigHeader>::try_from_file_offset(u64::MAX - 7),
+                   Err(TryFromFileOffsetError::TooLarge));
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        assert_eq!(Offset::<()>::ZERO.checked_add(1), Some(Offset::new(1)));
+        assert_eq!(Offset::<()>::MAX.checked_add(1), None);
+        assert_eq!(Offset::<()>::new(1).checked_add(usize::MAX), None);
+        assert_eq!(Offset::<()>::ZERO.checked_sub(1), None);
+        assert_eq!(Offset::<()>::new(1).checked_sub(1), Some(Offset::ZERO));
+    }
+
+    #[test]
+    #[should_panic(expected = "offset overflow")]
+    fn add_assign_overflow() {
+        let mut offset = Offset::<()>::MAX;
+        offset += 1;
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserialize() {
+        use serde::Deserialize;
+        use serde::de::value::{Error, U64Deserializer};
+
+        assert_eq!(Offset::<()>::deserialize(U64Deserializer::<Error>::new(42)),
+                   Ok(Offset::new(42)));
+        assert!(Offset::<()>::deserialize(U64Deserializer::<Error>::new(u64::MAX)).is_err());
     }
 }
@@ -0,0 +1,651 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Bound, Range, RangeBounds};
+
+use super::{Blobs, Breccia, Header, Offset, Search};
+
+/// The result of searching for a single key.
+type SearchResult<'a, H> = Result<(Offset<H>, &'a [u8]), Offset<H>>;
+
+impl<H: Header> Breccia<H> {
+    /// Returns the offset of the last visible end marker.
+    ///
+    /// This is where the next blob would be written, and is what the bound searches return when
+    /// every blob is before the target.
+    pub(crate) fn end_offset(&self) -> Offset<H> {
+        let blobs = Blobs::<H>::new(self.visible_map(), Offset::ZERO);
+        blobs.offset + blobs.map.len().saturating_sub(1)
+    }
+
+    /// Returns the offset of the first blob for which `pred` returns `false`, or the offset of the
+    /// last end marker if there is no such blob.
+    ///
+    /// The blobs must be partitioned: all blobs for which `pred` returns `Some(true)` must come
+    /// before all blobs for which it returns `Some(false)`. Blobs for which `pred` returns `None`
+    /// are skipped over, like `Search::Next`.
+    ///
+    /// The returned offset can be used as either end of a range passed to `blobs_in`.
+    pub fn partition_point<P>(&self, mut pred: P) -> Offset<H>
+        where P: FnMut(&[u8]) -> Option<bool>
+    {
+        let end = self.end_offset();
+        let mut point = end;
+        let found = self.binary_search_in_range(|offset, blob| {
+            match pred(blob) {
+                Some(true) => Err(Search::Right),
+                Some(false) => {
+                    point = point.min(offset);
+                    Err(Search::Left)
+                },
+                None => Err(Search::Next),
+            }
+        }, Offset::ZERO .. end);
+
+        match found {
+            // The search never returns a match, so it only stops once the point has been found.
+            Some(()) => unreachable!(),
+            None => point,
+        }
+    }
+
+    /// Returns the offset of the first blob whose key is not less than `key`.
+    ///
+    /// The blobs must be sorted by the key returned by `f`. Blobs for which `f` returns `None`
+    /// are skipped over.
+    pub fn lower_bound<K, F>(&self, key: &K, mut f: F) -> Offset<H>
+        where K: Ord,
+              F: FnMut(&[u8]) -> Option<K>,
+    {
+        self.partition_point(|blob| f(blob).map(|k| k < *key))
+    }
+
+    /// Returns the offset of the first blob whose key is greater than `key`.
+    ///
+    /// The blobs must be sorted by the key returned by `f`. Blobs for which `f` returns `None`
+    /// are skipped over.
+    pub fn upper_bound<K, F>(&self, key: &K, mut f: F) -> Offset<H>
+        where K: Ord,
+              F: FnMut(&[u8]) -> Option<K>,
+    {
+        self.partition_point(|blob| f(blob).map(|k| k <= *key))
+    }
+
+    /// Returns the range of offsets containing the blobs whose key is equal to `key`.
+    ///
+    /// Pass the range to `blobs_in` to iterate over the matching blobs. If no blob matches, the
+    /// range is empty, and starts where a blob with that key would be.
+    ///
+    /// Blobs for which `f` returns `None` are skipped over while searching, but any between or
+    /// just after the matching blobs lie within the range, so `blobs_in` returns them too. Use
+    /// `range_by_key` to iterate over only the matching blobs.
+    pub fn equal_range<K, F>(&self, key: &K, mut f: F) -> Range<Offset<H>>
+        where K: Ord,
+              F: FnMut(&[u8]) -> Option<K>,
+    {
+        let start = self.lower_bound(key, &mut f);
+        let end = self.upper_bound(key, &mut f);
+        start .. end
+    }
+
+    /// Binary searches with a comparator function, like `slice::binary_search_by`.
+    ///
+    /// `f` should return the ordering of a blob relative to the target, or `None` if the blob
+    /// should be skipped over, like `Search::Next`.
+    ///
+    /// If a matching blob is found, returns `Ok` with its offset and bytes. If there are multiple
+    /// matches, any one of them may be returned. Otherwise returns `Err` with the offset where a
+    /// matching blob would be; see `partition_point`.
+    pub fn search_by<F>(&self, f: F) -> Result<(Offset<H>, &[u8]), Offset<H>>
+        where F: FnMut(&[u8]) -> Option<Ordering>
+    {
+        self.search_in_range_by(f, Offset::ZERO .. self.end_offset())
+    }
+
+    /// Like `search_by`, but only searching the blobs that start within `range`.
+    ///
+    /// If nothing matches, the insertion point defaults to `range.end`.
+    pub(crate) fn search_in_range_by<F>(&self, mut f: F, range: Range<Offset<H>>) -> Result<(Offset<H>, &[u8]), Offset<H>>
+        where F: FnMut(&[u8]) -> Option<Ordering>
+    {
+        let mut point = range.end;
+        let found = self.binary_search_in_range(|offset, blob| {
+            match f(blob) {
+                Some(Ordering::Equal) => Ok(Some(offset)),
+                Some(Ordering::Less) => Err(Search::Right),
+                Some(Ordering::Greater) => {
+                    point = point.min(offset);
+                    Err(Search::Left)
+                },
+                None => Err(Search::Next),
+            }
+        }, range);
+
+        match found {
+            Some(offset) => {
+                let blob = self.get_blob(offset).expect("blobs found by searching are valid");
+                Ok((offset, blob.as_bytes()))
+            },
+            None => Err(point),
+        }
+    }
+
+    /// Binary searches for a key, like `slice::binary_search_by_key`.
+    ///
+    /// The blobs must be sorted by the key returned by `f`. Blobs for which `f` returns `None`
+    /// are skipped over.
+    pub fn search_by_key<K, F>(&self, key: &K, mut f: F) -> Result<(Offset<H>, &[u8]), Offset<H>>
+        where K: Ord,
+              F: FnMut(&[u8]) -> Option<K>,
+    {
+        self.search_by(|blob| f(blob).map(|k| k.cmp(key)))
+    }
+
+    /// Finds the first blob that starts at or after `start`, and before `end`, for which `f`
+    /// returns `Some`.
+    fn probe<K, F>(&self, start: Offset<H>, end: Offset<H>, f: &mut F) -> Option<(Offset<H>, &[u8], K)>
+        where F: FnMut(&[u8]) -> Option<K>
+    {
+        let map = self.visible_map().get(start.raw ..).unwrap_or(&[]);
+        Blobs::<H>::new(map, start).take_while(|(offset, _blob)| *offset < end)
+                                   .find_map(|(offset, blob)| Some((offset, blob, f(blob)?)))
+    }
+
+    /// Searches for a key using interpolation search.
+    ///
+    /// `f` maps each blob to a numeric position, which must increase with each blob. Rather than
+    /// always probing the midpoint, the next probe is placed in proportion to where `key` falls
+    /// between the positions of the blobs bounding the remaining range. When keys are close to
+    /// uniformly distributed this needs far fewer probes than `search_by_key`. Whenever a probe
+    /// fails to halve the remaining range, the next probe bisects it instead, so skewed keys need
+    /// at most about twice as many probes as a binary search.
+    ///
+    /// Blobs for which `f` returns `None` are skipped over. Returns the same results as
+    /// `search_by_key`.
+    pub fn interpolation_search_by_key<F>(&self, key: u64, mut f: F) -> Result<(Offset<H>, &[u8]), Offset<H>>
+        where F: FnMut(&[u8]) -> Option<u64>
+    {
+        let end = self.end_offset();
+
+        let Some((first, first_blob, first_key)) = self.probe(Offset::ZERO, end, &mut f) else {
+            return Err(end)
+        };
+        match first_key.cmp(&key) {
+            Ordering::Equal => return Ok((first, first_blob)),
+            Ordering::Greater => return Err(first),
+            Ordering::Less => {},
+        }
+
+        let map = self.visible_map().get(first.raw ..).unwrap_or(&[]);
+        let (last, last_blob, last_key) = Blobs::<H>::new(map, first).rev()
+            .find_map(|(offset, blob)| Some((offset, blob, f(blob)?)))
+            .expect("first blob has a key");
+        match last_key.cmp(&key) {
+            Ordering::Equal => return Ok((last, last_blob)),
+            Ordering::Less => return Err(end),
+            Ordering::Greater => {},
+        }
+
+        // Invariant: the target is after the blob with key `lo_key`, which is before `lo`, and
+        // before the blob with key `hi_key`, which is at or after `hi`.
+        let (mut lo, mut lo_key) = (first + 1, first_key);
+        let (mut hi, mut hi_key) = (last, last_key);
+        let mut point = last;
+        let mut bisect = false;
+
+        while lo < hi {
+            let len = hi.raw - lo.raw;
+            let probe = if !bisect && lo_key < key && key < hi_key {
+                let fraction = u128::from(key - lo_key) * len as u128 / u128::from(hi_key - lo_key);
+                lo + (fraction as usize).min(len - 1)
+            } else {
+                lo.midpoint(hi)
+            };
+
+            match self.probe(probe, hi, &mut f) {
+                Some((offset, blob, k)) => match k.cmp(&key) {
+                    Ordering::Equal => return Ok((offset, blob)),
+                    Ordering::Less => (lo, lo_key) = (offset + 1, k),
+                    Ordering::Greater => {
+                        (hi, hi_key) = (offset, k);
+                        point = offset;
+                    },
+                },
+
+                // No keyed blobs between the probe and `hi`, so the first keyed blob at or after
+                // `probe` is still the one with `hi_key`.
+                None => hi = probe,
+            }
+
+            bisect = !bisect && hi.raw.saturating_sub(lo.raw) > len / 2;
+        }
+
+        Err(point)
+    }
+
+    /// Searches for a key by galloping backwards from the end of the file.
+    ///
+    /// Starting with the last blob, probes are made at exponentially increasing distances from
+    /// the end until a blob with a key less than `key` is found, and then the range between the
+    /// last two probes is bisected. This takes time logarithmic in the distance of the target
+    /// from the end, rather than in the size of the file, so it's faster than `search_by_key` for
+    /// recently written blobs.
+    ///
+    /// The blobs must be sorted by the key returned by `f`. Blobs for which `f` returns `None`
+    /// are skipped over. Returns the same results as `search_by_key`.
+    pub fn gallop_search_by_key<K, F>(&self, key: &K, mut f: F) -> Result<(Offset<H>, &[u8]), Offset<H>>
+        where K: Ord,
+              F: FnMut(&[u8]) -> Option<K>,
+    {
+        // Invariant: every keyed blob at or after `hi` has a key greater than `key`.
+        let mut hi = self.end_offset();
+        let mut step = 0;
+        let lo = loop {
+            let probe = Offset::new(hi.raw.saturating_sub(step));
+            let found = self.blobs_before(probe).rev()
+                            .find_map(|(offset, blob)| Some((offset, blob, f(blob)?)));
+
+            match found {
+                Some((offset, blob, k)) => match k.cmp(key) {
+                    Ordering::Equal => return Ok((offset, blob)),
+                    Ordering::Less => break offset + 1,
+                    Ordering::Greater => {
+                        hi = offset;
+                        step = (step * 2).max(1);
+                    },
+                },
+                None => break Offset::ZERO,
+            }
+        };
+
+        self.search_in_range_by(|blob| f(blob).map(|k| k.cmp(key)), lo .. hi)
+    }
+
+    /// Searches for many keys at once.
+    ///
+    /// `keys` must be sorted. Probes are shared between keys: each probe partitions the keys, and
+    /// the search recurses into each side with only the keys that belong there. Looking up many
+    /// keys this way needs far fewer probes than calling `search_by_key` for each one.
+    ///
+    /// Returns one result per key, in the same order as `keys`, with the same meaning as the
+    /// result of `search_by_key`.
+    pub fn search_many<K, F>(&self, keys: &[K], mut f: F) -> Vec<SearchResult<'_, H>>
+        where K: Ord,
+              F: FnMut(&[u8]) -> Option<K>,
+    {
+        let end = self.end_offset();
+        let mut results = vec![Err(end); keys.len()];
+        self.search_many_in_range(keys, &mut results, &mut f, Offset::ZERO .. end, end);
+        results
+    }
+
+    /// Searches for `keys` among the blobs that start within `range`, writing the results to the
+    /// corresponding elements of `results`.
+    ///
+    /// `point` is the offset of the first keyed blob after `range` that's greater than all of
+    /// `keys`, which is where any missing keys would be.
+    fn search_many_in_range<'a, K, F>(&'a self,
+                                      keys: &[K],
+                                      results: &mut [SearchResult<'a, H>],
+                                      f: &mut F,
+                                      range: Range<Offset<H>>,
+                                      point: Offset<H>)
+        where K: Ord,
+              F: FnMut(&[u8]) -> Option<K>,
+    {
+        if keys.is_empty() {
+            return
+        } else if range.start >= range.end {
+            results.fill(Err(point));
+            return
+        }
+
+        let midpoint = range.start.midpoint(range.end);
+        let Some((offset, blob, k)) = self.probe(midpoint, range.end, f) else {
+            // There are no keyed blobs after the midpoint, so all the keys belong to the left.
+            return self.search_many_in_range(keys, results, f, range.start .. midpoint, point)
+        };
+
+        let less = keys.partition_point(|key| *key < k);
+        let equal = less + keys[less ..].partition_point(|key| *key == k);
+
+        let (left_results, rest) = results.split_at_mut(less);
+        let (equal_results, right_results) = rest.split_at_mut(equal - less);
+
+        equal_results.fill(Ok((offset, blob)));
+        self.search_many_in_range(&keys[.. less], left_results, f, range.start .. midpoint, offset);
+        self.search_many_in_range(&keys[equal ..], right_results, f, offset + 1 .. range.end, point);
+    }
+
+    /// Returns an iterator over the blobs whose key is within `range`.
+    ///
+    /// The blobs must be sorted by the key returned by `f`. Blobs for which `f` returns `None`
+    /// are never returned.
+    pub fn range_by_key<'a, K, R, F>(&'a self, range: R, mut f: F) -> RangeByKey<'a, H, F>
+        where K: Ord,
+              R: RangeBounds<K>,
+              F: FnMut(&[u8]) -> Option<K>,
+    {
+        let start = match range.start_bound() {
+            Bound::Included(lo) => self.lower_bound(lo, &mut f),
+            Bound::Excluded(lo) => self.upper_bound(lo, &mut f),
+            Bound::Unbounded => Offset::ZERO,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(hi) => self.upper_bound(hi, &mut f),
+            Bound::Excluded(hi) => self.lower_bound(hi, &mut f),
+            Bound::Unbounded => self.end_offset(),
+        };
+
+        // An inverted range, e.g. `5 .. 3`, is empty.
+        RangeByKey {
+            blobs: self.blobs_in(start .. end.max(start)),
+            f,
+        }
+    }
+}
+
+/// An iterator over the blobs with keys in a range, returned by `Breccia::range_by_key`.
+pub struct RangeByKey<'a, H, F> {
+    blobs: Blobs<'a, H>,
+    f: F,
+}
+
+impl<H, F> fmt::Debug for RangeByKey<'_, H, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RangeByKey")
+            .field("blobs", &self.blobs)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, H: Header, K, F> Iterator for RangeByKey<'a, H, F>
+    where F: FnMut(&[u8]) -> Option<K>
+{
+    type Item = (Offset<H>, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let f = &mut self.f;
+        self.blobs.find(|(_offset, blob)| f(blob).is_some())
+    }
+}
+
+impl<H: Header, K, F> std::iter::FusedIterator for RangeByKey<'_, H, F>
+    where F: FnMut(&[u8]) -> Option<K>
+{
+}
+
+impl<H: Header, K, F> DoubleEndedIterator for RangeByKey<'_, H, F>
+    where F: FnMut(&[u8]) -> Option<K>
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let f = &mut self.f;
+        self.blobs.rfind(|(_offset, blob)| f(blob).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+
+    use crate::test_util::{assert_search_result, breccia_of_keys, key};
+
+    /// The expected partition point, found by a linear scan.
+    fn linear_partition_point(b: &Breccia, mut pred: impl FnMut(u32) -> bool) -> Offset<()> {
+        b.blobs().find(|(_, blob)| !pred(key(blob).unwrap()))
+                 .map_or(b.committed().unwrap(), |(offset, _)| offset)
+    }
+
+    #[test]
+    fn bounds_on_empty_breccia() -> io::Result<()> {
+        let b = breccia_of_keys(&[], 0)?;
+        assert_eq!(b.partition_point(|_| panic!("should not be called")), Offset::ZERO);
+        assert_eq!(b.equal_range(&1, key), Offset::ZERO .. Offset::ZERO);
+        Ok(())
+    }
+
+    #[test]
+    fn bounds_with_duplicates() -> io::Result<()> {
+        let keys = [1, 1, 2, 3, 3, 3, 3, 5, 8, 8, 13];
+        for padding in [0, 100] {
+            let b = breccia_of_keys(&keys, padding)?;
+
+            for target in 0 .. 15 {
+                assert_eq!(b.lower_bound(&target, key), linear_partition_point(&b, |k| k < target));
+                assert_eq!(b.upper_bound(&target, key), linear_partition_point(&b, |k| k <= target));
+
+                let matching: Vec<u32> = b.blobs_in(b.equal_range(&target, key))
+                                          .map(|(_, blob)| key(blob).unwrap())
+                                          .collect();
+                let expected: Vec<u32> = keys.iter().copied().filter(|k| *k == target).collect();
+                assert_eq!(matching, expected);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn search_by_key_hits_and_misses() -> io::Result<()> {
+        let keys = [1, 3, 3, 5, 8];
+        for padding in [0, 100] {
+            let b = breccia_of_keys(&keys, padding)?;
+
+            for target in 0 .. 10 {
+                assert_search_result(&b, target, b.search_by_key(&target, key));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn search_by_skips_unkeyed_blobs() -> io::Result<()> {
+        let mut b = breccia_of_keys(&[], 0)?;
+        let mut batch = b.start_batch()?;
+        batch.write_blob(&[])?;
+        let two = batch.write_blob(&2u32.to_le_bytes())?;
+        batch.write_blob(&[])?;
+        batch.write_blob(&[])?;
+        batch.commit()?;
+
+        assert_eq!(b.search_by(|blob| key(blob).map(|k| k.cmp(&2))),
+                   Ok((two, &2u32.to_le_bytes()[..])));
+        assert_eq!(b.search_by_key(&1, key), Err(two));
+        assert_eq!(b.search_by_key(&3, key), Err(b.committed().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn range_by_key() -> io::Result<()> {
+        let keys = [1, 1, 2, 3, 3, 5, 8, 8, 13];
+        let mut b = breccia_of_keys(&keys, 0)?;
+        b.write_blob(&[])?;
+
+        let scan = |range: (Bound<u32>, Bound<u32>)| {
+            let forwards: Vec<u32> = b.range_by_key(range, key).map(|(_, blob)| key(blob).unwrap()).collect();
+            let mut backwards: Vec<u32> = b.range_by_key(range, key).rev().map(|(_, blob)| key(blob).unwrap()).collect();
+            backwards.reverse();
+            assert_eq!(forwards, backwards);
+            forwards
+        };
+
+        for lo in 0 .. 15 {
+            for hi in 0 .. 15 {
+                for range in [(Bound::Included(lo), Bound::Included(hi)),
+                              (Bound::Included(lo), Bound::Excluded(hi)),
+                              (Bound::Excluded(lo), Bound::Included(hi)),
+                              (Bound::Excluded(lo), Bound::Excluded(hi)),
+                              (Bound::Unbounded, Bound::Included(hi)),
+                              (Bound::Excluded(lo), Bound::Unbounded)]
+                {
+                    let expected: Vec<u32> = keys.iter().copied().filter(|k| range.contains(k)).collect();
+                    assert_eq!(scan(range), expected, "{range:?}");
+                }
+            }
+        }
+
+        assert_eq!(scan((Bound::Unbounded, Bound::Unbounded)), keys);
+
+        let mut range = b.range_by_key(2 ..= 5, key);
+        assert_eq!(range.next().map(|(_, blob)| key(blob)), Some(Some(2)));
+        assert_eq!(range.next_back().map(|(_, blob)| key(blob)), Some(Some(5)));
+        assert_eq!(range.next_back().map(|(_, blob)| key(blob)), Some(Some(3)));
+        assert_eq!(range.next().map(|(_, blob)| key(blob)), Some(Some(3)));
+        assert_eq!(range.next(), None);
+        assert_eq!(range.next_back(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn interpolation_search() -> io::Result<()> {
+        let key64 = |blob: &[u8]| Some(u64::from(key(blob)?));
+
+        for keys in [(0 .. 1000).map(|i| i * 10).collect::<Vec<u32>>(),
+                     (0 .. 1000).map(|i| i * i * i / 1000).collect(),
+                     vec![3]]
+        {
+            let b = breccia_of_keys(&keys, 0)?;
+            let max_probes = 2 * (keys.len().ilog2() as usize + 1) + 2;
+
+            for target in (0 .. 11).chain(keys.iter().copied()).chain(keys.iter().map(|k| k + 1)) {
+                let mut probes = 0;
+                let found = b.interpolation_search_by_key(u64::from(target), |blob| {
+                    probes += 1;
+                    key64(blob)
+                });
+                assert!(probes <= max_probes, "{probes} probes for {target}");
+
+                assert_search_result(&b, target, found);
+            }
+        }
+
+        // Evenly spaced keys are found with the first interpolated probe.
+        let b = breccia_of_keys(&(0 .. 1000).map(|i| i * 10).collect::<Vec<u32>>(), 0)?;
+        let mut probes = 0;
+        assert!(b.interpolation_search_by_key(4560, |blob| { probes += 1; key64(blob) }).is_ok());
+        assert_eq!(probes, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn interpolation_search_skips_unkeyed_blobs() -> io::Result<()> {
+        let mut b = breccia_of_keys(&[], 0)?;
+        assert_eq!(b.interpolation_search_by_key(1, |_| panic!("should not be called")), Err(Offset::ZERO));
+
+        let mut batch = b.start_batch()?;
+        batch.write_blob(&[])?;
+        let two = batch.write_blob(&2u32.to_le_bytes())?;
+        batch.write_blob(&[])?;
+        let four = batch.write_blob(&4u32.to_le_bytes())?;
+        batch.write_blob(&[])?;
+        batch.commit()?;
+
+        let search = |target| b.interpolation_search_by_key(target, |blob| Some(u64::from(key(blob)?)));
+        assert_eq!(search(1), Err(two));
+        assert_eq!(search(2).map(|(offset, _)| offset), Ok(two));
+        assert_eq!(search(3), Err(four));
+        assert_eq!(search(4).map(|(offset, _)| offset), Ok(four));
+        assert_eq!(search(5), Err(b.committed().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn gallop_search() -> io::Result<()> {
+        let keys: Vec<u32> = (0 .. 1000).map(|i| i * 3 / 2).collect();
+        for padding in [0, 100] {
+            let b = breccia_of_keys(&keys, padding)?;
+
+            for target in 0 .. 1502 {
+                assert_search_result(&b, target, b.gallop_search_by_key(&target, key));
+            }
+
+            // Recent blobs only need a few probes.
+            let mut probes = 0;
+            assert!(b.gallop_search_by_key(&keys[keys.len() - 1], |blob| { probes += 1; key(blob) }).is_ok());
+            assert_eq!(probes, 1);
+
+            let mut probes = 0;
+            assert!(b.gallop_search_by_key(&keys[keys.len() - 5], |blob| { probes += 1; key(blob) }).is_ok());
+            assert!(probes <= 6, "{probes} probes");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn gallop_search_skips_unkeyed_blobs() -> io::Result<()> {
+        let mut b = breccia_of_keys(&[], 0)?;
+        assert_eq!(b.gallop_search_by_key(&1, |_| panic!("should not be called")), Err(Offset::ZERO));
+
+        let mut batch = b.start_batch()?;
+        batch.write_blob(&[])?;
+        let two = batch.write_blob(&2u32.to_le_bytes())?;
+        batch.write_blob(&[])?;
+        let four = batch.write_blob(&4u32.to_le_bytes())?;
+        batch.write_blob(&[])?;
+        batch.commit()?;
+
+        assert_eq!(b.gallop_search_by_key(&1, key), Err(two));
+        assert_eq!(b.gallop_search_by_key(&2, key).map(|(offset, _)| offset), Ok(two));
+        assert_eq!(b.gallop_search_by_key(&3, key), Err(four));
+        assert_eq!(b.gallop_search_by_key(&4, key).map(|(offset, _)| offset), Ok(four));
+        assert_eq!(b.gallop_search_by_key(&5, key), Err(b.committed().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn search_many() -> io::Result<()> {
+        let keys: Vec<u32> = (0 .. 500).map(|i| i * 3 / 2).collect();
+        for padding in [0, 100] {
+            let mut b = breccia_of_keys(&keys, padding)?;
+            b.write_blob(&[])?;
+
+            assert_eq!(b.search_many(&[], key), vec![]);
+
+            let targets: Vec<u32> = (0 .. 800).chain([800, 800, 801]).collect();
+            let mut probes = 0;
+            let results = b.search_many(&targets, |blob| { probes += 1; key(blob) });
+            assert_eq!(results.len(), targets.len());
+
+            let mut single_probes = 0;
+            for (target, result) in targets.iter().zip(results) {
+                let single = b.search_by_key(target, |blob| { single_probes += 1; key(blob) });
+                assert_eq!(result.is_ok(), single.is_ok());
+
+                assert_search_result(&b, *target, result);
+            }
+            assert!(probes * 4 < single_probes, "{probes} probes vs {single_probes}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn equal_range_includes_unkeyed_blobs() -> io::Result<()> {
+        let mut b = breccia_of_keys(&[1, 2], 0)?;
+        let unkeyed = b.write_blob(&[0xff])?;
+        b.write_blob(&3u32.to_le_bytes())?;
+
+        let range = b.equal_range(&2, key);
+        assert_eq!(b.blobs_in(range.clone()).map(|(_, blob)| key(blob)).collect::<Vec<_>>(), vec![Some(2), None]);
+        assert!(range.end > unkeyed);
+        assert_eq!(b.range_by_key(2 ..= 2, key).count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn bounds_skip_unkeyed_blobs() -> io::Result<()> {
+        let mut b = breccia_of_keys(&[1, 2], 0)?;
+        let unkeyed = b.write_blob(&[0xff])?;
+        let mut batch = b.start_batch()?;
+        let three = batch.write_blob(&3u32.to_le_bytes())?;
+        batch.write_blob(&[])?;
+        batch.commit()?;
+
+        assert_eq!(b.lower_bound(&3, key), three);
+        assert_eq!(b.upper_bound(&2, key), three);
+        assert!(b.upper_bound(&2, key) > unkeyed);
+        assert_eq!(b.upper_bound(&3, key), b.committed().unwrap());
+        Ok(())
+    }
+}
@@ -0,0 +1,342 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Blobs, Breccia, BrecciaMut, Header, Marker};
+
+/// Options for `sort_into_with_options`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortOptions {
+    /// Roughly how many bytes of blobs are sorted in memory at once.
+    ///
+    /// Each run of this many bytes is written to a temporary file, and the runs are then merged.
+    /// If the whole source fits in a single run, no temporary files are needed.
+    pub run_bytes: usize,
+
+    /// Roughly how many bytes are written to the destination in each committed batch.
+    pub commit_bytes: usize,
+
+    /// The most runs to merge at once, each of which holds a file open. Once this many runs have
+    /// been written they're merged into a single, larger run. Values less than 2 are treated as 2.
+    pub max_fan_in: usize,
+
+    /// The directory to write runs to. Defaults to `std::env::temp_dir()`.
+    pub temp_dir: Option<PathBuf>,
+}
+
+impl Default for SortOptions {
+    fn default() -> Self {
+        Self {
+            run_bytes: 64 * 1024 * 1024,
+            commit_bytes: 16 * 1024 * 1024,
+            max_fan_in: 64,
+            temp_dir: None,
+        }
+    }
+}
+
+/// Copies the visible blobs of `src` to `dst`, sorted by the key returned by `f`.
+///
+/// Uses the default `SortOptions`; see `sort_into_with_options`.
+pub fn sort_into<H, H2, K, F>(src: &Breccia<H>, dst: BrecciaMut<H2>, f: F) -> io::Result<BrecciaMut<H2>>
+    where H: Header,
+          H2: Header,
+          K: Ord,
+          F: FnMut(&[u8]) -> Option<K>,
+{
+    sort_into_with_options(src, dst, f, &SortOptions::default())
+}
+
+/// Copies the visible blobs of `src` to `dst`, sorted by the key returned by `f`, using an
+/// external merge sort so that `src` can be much larger than memory.
+///
+/// The sort is stable: blobs with equal keys keep their order. Blobs for which `f` returns `None`
+/// are written first. The blobs are appended to `dst`, which should be empty, or only contain
+/// blobs with keys no greater than those in `src`.
+///
+/// Runs are written to temporary files that are unlinked as soon as they're created, so they're
+/// cleaned up even if sorting fails. They're never synced to disk, as they don't need to survive
+/// a crash.
+///
+/// The output is written to `dst` in batches of roughly `commit_bytes`, each committed as it's
+/// written. If sorting fails part-way, `dst` is dropped, and its file is left holding the batches
+/// committed so far: a prefix of the sorted output, which is itself sorted.
+pub fn sort_into_with_options<H, H2, K, F>(src: &Breccia<H>,
+                                           mut dst: BrecciaMut<H2>,
+                                           mut f: F,
+                                           options: &SortOptions) -> io::Result<BrecciaMut<H2>>
+    where H: Header,
+          H2: Header,
+          K: Ord,
+          F: FnMut(&[u8]) -> Option<K>,
+{
+    let mut runs = Runs {
+        levels: vec![],
+        max_fan_in: options.max_fan_in.max(2),
+        temp_dir: options.temp_dir.clone().unwrap_or_else(std::env::temp_dir),
+    };
+
+    let mut run: Vec<(Option<K>, &[u8])> = vec![];
+    let mut run_bytes = 0;
+    for (_offset, blob) in src.blobs() {
+        run.push((f(blob), blob));
+        run_bytes += blob.len() + size_of::<(Option<K>, &[u8])>();
+
+        if run_bytes >= options.run_bytes {
+            runs.push_sorted(&mut run, &mut f)?;
+            run_bytes = 0;
+        }
+    }
+
+    if runs.levels.is_empty() {
+        // Everything fit in memory, so there's nothing to merge.
+        run.sort_by(|(a, _), (b, _)| a.cmp(b));
+        write_batched(&mut dst, run.into_iter().map(|(_key, blob)| blob), options.commit_bytes)?;
+        return Ok(dst)
+    } else if !run.is_empty() {
+        runs.push_sorted(&mut run, &mut f)?;
+    }
+
+    let runs = runs.finish(&mut f)?;
+    let merge = Merge::new(runs.iter().map(|run| run.blobs()).collect(), &mut f);
+    write_batched(&mut dst, merge, options.commit_bytes)?;
+    Ok(dst)
+}
+
+/// The sorted runs written so far.
+struct Runs {
+    /// Runs grouped by how many times they've been merged, so that each blob is only rewritten
+    /// a logarithmic number of times. Runs in later levels hold earlier blobs, and each level is
+    /// in order of its runs' blobs.
+    levels: Vec<Vec<BrecciaMut<()>>>,
+
+    max_fan_in: usize,
+    temp_dir: PathBuf,
+}
+
+impl Runs {
+    /// Sorts a run, stably, and writes it to a temporary file, leaving `run` empty.
+    fn push_sorted<K, F>(&mut self, run: &mut Vec<(Option<K>, &[u8])>, f: &mut F) -> io::Result<()>
+        where K: Ord,
+              F: FnMut(&[u8]) -> Option<K>,
+    {
+        run.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let run = self.write_run(run.drain(..).map(|(_key, blob)| blob))?;
+        self.push(0, run, f)
+    }
+
+    /// Adds a run to `level`, merging the level into the next once it's full.
+    fn push<K, F>(&mut self, level: usize, run: BrecciaMut<()>, f: &mut F) -> io::Result<()>
+        where K: Ord,
+              F: FnMut(&[u8]) -> Option<K>,
+    {
+        if level == self.levels.len() {
+            self.levels.push(vec![]);
+        }
+        self.levels[level].push(run);
+
+        if self.levels[level].len() >= self.max_fan_in {
+            let full = std::mem::take(&mut self.levels[level]);
+            let merged = self.merge(&full, f)?;
+            drop(full);
+            self.push(level + 1, merged, f)?;
+        }
+        Ok(())
+    }
+
+    /// Merges the levels down to at most `max_fan_in` runs, returned in order of their blobs.
+    fn finish<K, F>(mut self, f: &mut F) -> io::Result<Vec<BrecciaMut<()>>>
+        where K: Ord,
+              F: FnMut(&[u8]) -> Option<K>,
+    {
+        let mut runs: Vec<_> = std::mem::take(&mut self.levels).into_iter().rev().flatten().collect();
+        while runs.len() > self.max_fan_in {
+            let merged = self.merge(&runs[.. self.max_fan_in], f)?;
+            runs.splice(.. self.max_fan_in, [merged]);
+        }
+        Ok(runs)
+    }
+
+    /// Merges consecutive runs into a new run.
+    fn merge<K, F>(&self, runs: &[BrecciaMut<()>], f: &mut F) -> io::Result<BrecciaMut<()>>
+        where K: Ord,
+              F: FnMut(&[u8]) -> Option<K>,
+    {
+        self.write_run(Merge::new(runs.iter().map(|run| run.blobs()).collect(), f))
+    }
+
+    /// Writes blobs to a new temporary run, as a single unsynced batch.
+    fn write_run<'a>(&self, blobs: impl Iterator<Item = &'a [u8]>) -> io::Result<BrecciaMut<()>> {
+        let mut run = BrecciaMut::create_from_file(temp_file(&self.temp_dir)?, ())?;
+        let mut batch = run.start_batch()?;
+        for blob in blobs {
+            batch.write_blob(blob)?;
+        }
+        batch.commit_unsynced()?;
+        Ok(run)
+    }
+}
+
+/// Creates a file in `dir` that's unlinked as soon as it's created.
+fn temp_file(dir: &Path) -> io::Result<File> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    loop {
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!(".breccia-sort-{}-{n}", process::id()));
+        match OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+            Ok(fd) => {
+                fs::remove_file(&path)?;
+                break Ok(fd)
+            },
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => break Err(err),
+        }
+    }
+}
+
+/// Writes blobs to `dst`, committing roughly every `commit_bytes`.
+fn write_batched<'a, H: Header>(dst: &mut BrecciaMut<H>,
+                                blobs: impl Iterator<Item = &'a [u8]>,
+                                commit_bytes: usize) -> io::Result<()>
+{
+    let mut blobs = blobs.peekable();
+    while blobs.peek().is_some() {
+        let mut batch = dst.start_batch()?;
+        let mut written = 0;
+        while written < commit_bytes && let Some(blob) = blobs.next() {
+            batch.write_blob(blob)?;
+            written += blob.len() + size_of::<Marker>();
+        }
+        batch.commit()?;
+    }
+    Ok(())
+}
+
+/// A k-way merge of sorted runs.
+struct Merge<'a, 'f, K, F> {
+    runs: Vec<Blobs<'a, ()>>,
+
+    /// The next blob from each run that has yet to be returned.
+    heads: Vec<&'a [u8]>,
+
+    /// The key and run index of each head. Ties are broken by run index, keeping the merge stable.
+    heap: BinaryHeap<Reverse<(Option<K>, usize)>>,
+
+    f: &'f mut F,
+}
+
+impl<'a, 'f, K: Ord, F> Merge<'a, 'f, K, F>
+    where F: FnMut(&[u8]) -> Option<K>
+{
+    fn new(mut runs: Vec<Blobs<'a, ()>>, f: &'f mut F) -> Self {
+        let mut heads = vec![];
+        let mut heap = BinaryHeap::with_capacity(runs.len());
+        for (i, run) in runs.iter_mut().enumerate() {
+            let (_offset, blob) = run.next().expect("runs are not empty");
+            heads.push(blob);
+            heap.push(Reverse((f(blob), i)));
+        }
+        Self { runs, heads, heap, f }
+    }
+}
+
+impl<'a, K: Ord, F> Iterator for Merge<'a, '_, K, F>
+    where F: FnMut(&[u8]) -> Option<K>
+{
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_key, i)) = self.heap.pop()?;
+        let blob = self.heads[i];
+
+        if let Some((_offset, next)) = self.runs[i].next() {
+            self.heads[i] = next;
+            self.heap.push(Reverse(((self.f)(next), i)));
+        }
+        Some(blob)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::{tempdir, tempfile};
+
+    use crate::test_util::key;
+
+    /// Writes blobs with random keys, each tagged with its index so that stability can be checked.
+    fn unsorted_breccia(n: u32) -> io::Result<BrecciaMut> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, ())?;
+        let mut batch = b.start_batch()?;
+        for i in 0 .. n {
+            // Every tenth blob is just the index, which is too short to have a key.
+            let mut blob = vec![];
+            if i % 10 != 0 {
+                blob.extend(rand::random_range(0 .. n / 4).to_le_bytes());
+                blob.extend(i.to_le_bytes());
+                blob.resize(blob.len() + rand::random_range(0 .. 20), 0);
+            } else {
+                blob.extend(i.to_le_bytes());
+            }
+            batch.write_blob(&blob)?;
+        }
+        batch.commit()?;
+        Ok(b)
+    }
+
+    /// The key of a blob written by `unsorted_breccia`, which has no key if it's too short.
+    fn tagged_key(blob: &[u8]) -> Option<u32> {
+        if blob.len() >= 8 { key(blob) } else { None }
+    }
+
+    fn expected(src: &Breccia) -> Vec<Vec<u8>> {
+        let mut blobs: Vec<Vec<u8>> = src.blobs().map(|(_, blob)| blob.to_vec()).collect();
+        blobs.sort_by_key(|blob| tagged_key(blob));
+        blobs
+    }
+
+    #[test]
+    fn sort_in_memory() -> io::Result<()> {
+        let src = unsorted_breccia(200)?;
+        let dst = sort_into(&src, BrecciaMut::create_from_file(tempfile()?, ())?, tagged_key)?;
+
+        assert_eq!(dst.blobs().map(|(_, blob)| blob.to_vec()).collect::<Vec<_>>(), expected(&src));
+        assert_eq!(dst.check_sorted_by_key(tagged_key), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn sort_with_many_runs() -> io::Result<()> {
+        let dir = tempdir()?;
+        let options = SortOptions {
+            run_bytes: 500,
+            commit_bytes: 200,
+            max_fan_in: 3,
+            temp_dir: Some(dir.path().to_owned()),
+        };
+
+        let src = unsorted_breccia(1000)?;
+        let dst = sort_into_with_options(&src, BrecciaMut::create_from_file(tempfile()?, ())?, tagged_key, &options)?;
+
+        assert_eq!(dst.blobs().map(|(_, blob)| blob.to_vec()).collect::<Vec<_>>(), expected(&src));
+        assert_eq!(dst.check_sorted_by_key(tagged_key), Ok(()));
+
+        // Runs were unlinked as soon as they were created.
+        assert_eq!(fs::read_dir(dir.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn sort_empty() -> io::Result<()> {
+        let src = BrecciaMut::create_from_file(tempfile()?, ())?;
+        let dst = sort_into(&src, BrecciaMut::create_from_file(tempfile()?, ())?, key)?;
+        assert_eq!(dst.blobs().count(), 0);
+        Ok(())
+    }
+}
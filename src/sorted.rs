@@ -0,0 +1,419 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::io;
+use std::ops::{self, Range};
+
+use super::{Batch, Breccia, BrecciaMut, Header, Offset, Recovery};
+
+/// Errors that can occur when writing to a `SortedBrecciaMut`.
+#[derive(thiserror::Error, Debug)]
+pub enum SortedWriteError {
+    /// The blob's key is less than the key of the last blob written.
+    #[error("blob key is less than the last key written")]
+    OutOfOrder,
+
+    /// The blob's key is equal to the key of the last blob written, and duplicates are rejected.
+    #[error("blob key is equal to the last key written")]
+    Duplicate,
+
+    /// An I/O error occurred.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl From<SortedWriteError> for io::Error {
+    fn from(err: SortedWriteError) -> Self {
+        match err {
+            SortedWriteError::Io(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidInput, err),
+        }
+    }
+}
+
+/// A `BrecciaMut` that only allows blobs to be written in sorted order, so that it can always be
+/// searched.
+///
+/// Keys are extracted from blobs by `f`, as with `Breccia::search_by_key`. Blobs for which `f`
+/// returns `None` are skipped over by searches, so they can be written anywhere.
+///
+/// Derefs to `Breccia`, so the usual read and search methods are available.
+pub struct SortedBrecciaMut<H, K, F> {
+    inner: BrecciaMut<H>,
+    f: F,
+
+    /// The key of the last committed blob with a key.
+    last_key: Option<K>,
+
+    reject_duplicates: bool,
+}
+
+impl<H: fmt::Debug, K: fmt::Debug, F> fmt::Debug for SortedBrecciaMut<H, K, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SortedBrecciaMut")
+            .field("inner", &self.inner)
+            .field("last_key", &self.last_key)
+            .field("reject_duplicates", &self.reject_duplicates)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<H, K, F> ops::Deref for SortedBrecciaMut<H, K, F> {
+    type Target = Breccia<H>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<H, K, F> ops::DerefMut for SortedBrecciaMut<H, K, F> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<H: Header, K: Ord, F> SortedBrecciaMut<H, K, F>
+    where F: Fn(&[u8]) -> Option<K>
+{
+    /// Wraps a `BrecciaMut`, whose blobs must already be sorted by the key returned by `f`.
+    ///
+    /// The last key is recovered from the last committed blob with a key.
+    pub fn new(inner: BrecciaMut<H>, f: F) -> Self {
+        let last_key = Self::find_last_key(&inner, &f);
+        Self {
+            inner,
+            f,
+            last_key,
+            reject_duplicates: false,
+        }
+    }
+
+    fn find_last_key(breccia: &Breccia<H>, f: &F) -> Option<K> {
+        let committed = breccia.committed()?;
+        breccia.blobs_in(Offset::ZERO .. committed).rev().find_map(|(_offset, blob)| f(blob))
+    }
+
+    /// Sets whether blobs with the same key as the last blob are rejected.
+    ///
+    /// Duplicates are allowed by default.
+    pub fn set_reject_duplicates(&mut self, reject_duplicates: bool) {
+        self.reject_duplicates = reject_duplicates;
+    }
+
+    /// Returns `true` if blobs with the same key as the last blob are rejected.
+    pub fn rejects_duplicates(&self) -> bool {
+        self.reject_duplicates
+    }
+
+    /// Returns the key of the last committed blob with a key.
+    pub fn last_key(&self) -> Option<&K> {
+        self.last_key.as_ref()
+    }
+
+    /// Unwraps this into the underlying `BrecciaMut`.
+    pub fn into_inner(self) -> BrecciaMut<H> {
+        self.inner
+    }
+
+    /// Writes a new blob to the breccia, if its key is in order.
+    ///
+    /// Returns the `Offset` of the written blob.
+    pub fn write_blob(&mut self, blob: &[u8]) -> Result<Offset<H>, SortedWriteError> {
+        let mut batch = self.start_batch()?;
+        let offset = batch.write_blob(blob)?;
+        batch.commit()?;
+        Ok(offset)
+    }
+
+    /// Starts a new `SortedBatch` of blobs.
+    ///
+    /// Fails if the breccia ends with data from a batch that was never committed; use `recover` to
+    /// discard it first.
+    pub fn start_batch(&mut self) -> io::Result<SortedBatch<'_, H, K, F>> {
+        Ok(SortedBatch {
+            batch: self.inner.start_batch()?,
+            f: &self.f,
+            last_key: &mut self.last_key,
+            batch_last_key: None,
+            reject_duplicates: self.reject_duplicates,
+        })
+    }
+
+    /// Recovers from a batch that was interrupted before it was committed.
+    ///
+    /// See `BrecciaMut::recover`.
+    pub fn recover(&mut self) -> io::Result<Recovery<H>> {
+        let recovery = self.inner.recover()?;
+        self.last_key = Self::find_last_key(&self.inner, &self.f);
+        Ok(recovery)
+    }
+}
+
+/// Batch writing to a `SortedBrecciaMut`.
+///
+/// Each blob's key is checked against the last key written, including earlier blobs in the same
+/// batch. A rejected blob isn't written, but the batch can still be committed.
+pub struct SortedBatch<'a, H, K, F> {
+    batch: Batch<'a, H>,
+    f: &'a F,
+
+    /// The last committed key, updated when the batch is committed.
+    last_key: &'a mut Option<K>,
+
+    /// The last key written in this batch.
+    batch_last_key: Option<K>,
+
+    reject_duplicates: bool,
+}
+
+impl<H: fmt::Debug, K: fmt::Debug, F> fmt::Debug for SortedBatch<'_, H, K, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SortedBatch")
+            .field("batch", &self.batch)
+            .field("last_key", &self.last_key)
+            .field("batch_last_key", &self.batch_last_key)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<H: Header, K: Ord, F> SortedBatch<'_, H, K, F>
+    where F: Fn(&[u8]) -> Option<K>
+{
+    /// Writes a blob to the batch, if its key is in order.
+    ///
+    /// Returns the offset of the new blob.
+    pub fn write_blob(&mut self, blob: &[u8]) -> Result<Offset<H>, SortedWriteError> {
+        let key = (self.f)(blob);
+        if let Some(key) = &key &&
+           let Some(last_key) = self.batch_last_key.as_ref().or(self.last_key.as_ref())
+        {
+            match key.cmp(last_key) {
+                Ordering::Less => return Err(SortedWriteError::OutOfOrder),
+                Ordering::Equal if self.reject_duplicates => return Err(SortedWriteError::Duplicate),
+                Ordering::Equal | Ordering::Greater => {},
+            }
+        }
+
+        let offset = self.batch.write_blob(blob)?;
+        if key.is_some() {
+            self.batch_last_key = key;
+        }
+        Ok(offset)
+    }
+
+    /// Commits the batch.
+    pub fn commit(self) -> io::Result<()> {
+        self.batch.commit()?;
+        if let Some(key) = self.batch_last_key {
+            *self.last_key = Some(key);
+        }
+        Ok(())
+    }
+
+    /// Discards every blob written to the batch.
+    pub fn abort(self) -> io::Result<()> {
+        self.batch.abort()
+    }
+}
+
+/// A pair of adjacent blobs that are out of order, found by `Breccia::check_sorted_by_key`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SortViolation<H> {
+    /// The offset of the earlier blob, which has the greater key.
+    pub prev: Offset<H>,
+
+    /// The offset of the later blob, which has the lesser key.
+    pub next: Offset<H>,
+}
+
+impl<H: Header> Breccia<H> {
+    /// Checks that the visible blobs are sorted by the key returned by `f`, so that searching by
+    /// that key is safe.
+    ///
+    /// Equal keys are allowed. Blobs for which `f` returns `None` are skipped over, as they are by
+    /// searches. Returns the first pair of keyed blobs that are out of order.
+    pub fn check_sorted_by_key<K, F>(&self, mut f: F) -> Result<(), SortViolation<H>>
+        where K: Ord,
+              F: FnMut(&[u8]) -> Option<K>,
+    {
+        let mut last: Option<(Offset<H>, K)> = None;
+        for (offset, blob) in self.blobs() {
+            let Some(key) = f(blob) else { continue };
+            if let Some((prev, last_key)) = &last && key < *last_key {
+                return Err(SortViolation { prev: *prev, next: offset })
+            }
+            last = Some((offset, key));
+        }
+        Ok(())
+    }
+
+    /// Splits the visible blobs into maximal runs that are sorted by the key returned by `f`.
+    ///
+    /// Each range can be passed to `blobs_in`, and searched with `binary_search_in_range`. The
+    /// ranges cover every visible blob; blobs for which `f` returns `None` are included in
+    /// whichever run they fall within. A sorted breccia has a single run, and an empty one has
+    /// none.
+    pub fn sorted_runs_by_key<K, F>(&self, mut f: F) -> Vec<Range<Offset<H>>>
+        where K: Ord,
+              F: FnMut(&[u8]) -> Option<K>,
+    {
+        let mut runs = vec![];
+        let mut start = None;
+        let mut last_key = None;
+        for (offset, blob) in self.blobs() {
+            let run_start = *start.get_or_insert(Offset::ZERO);
+            let Some(key) = f(blob) else { continue };
+            if let Some(last_key) = &last_key && key < *last_key {
+                runs.push(run_start .. offset);
+                start = Some(offset);
+            }
+            last_key = Some(key);
+        }
+
+        if let Some(start) = start {
+            runs.push(start .. self.end_offset());
+        }
+        runs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use tempfile::tempfile;
+
+    use crate::test_util::key;
+
+    type KeyFn = fn(&[u8]) -> Option<u32>;
+
+    fn sorted_breccia() -> io::Result<SortedBrecciaMut<(), u32, KeyFn>> {
+        Ok(SortedBrecciaMut::new(BrecciaMut::create_from_file(tempfile()?, ())?, key))
+    }
+
+    fn kind(result: Result<Offset<()>, SortedWriteError>) -> Result<(), &'static str> {
+        match result {
+            Ok(_) => Ok(()),
+            Err(SortedWriteError::OutOfOrder) => Err("out of order"),
+            Err(SortedWriteError::Duplicate) => Err("duplicate"),
+            Err(SortedWriteError::Io(err)) => panic!("{err}"),
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_order_blobs() -> io::Result<()> {
+        let mut b = sorted_breccia()?;
+        assert_eq!(b.last_key(), None);
+
+        assert_eq!(kind(b.write_blob(&2u32.to_le_bytes())), Ok(()));
+        assert_eq!(kind(b.write_blob(&1u32.to_le_bytes())), Err("out of order"));
+        assert_eq!(kind(b.write_blob(&2u32.to_le_bytes())), Ok(()));
+        assert_eq!(b.last_key(), Some(&2));
+
+        b.set_reject_duplicates(true);
+        assert_eq!(kind(b.write_blob(&2u32.to_le_bytes())), Err("duplicate"));
+
+        // Blobs without keys can go anywhere.
+        assert_eq!(kind(b.write_blob(&[])), Ok(()));
+        assert_eq!(b.last_key(), Some(&2));
+
+        // Keys are checked against earlier blobs in the same batch, and rejected blobs aren't
+        // written.
+        let mut batch = b.start_batch()?;
+        assert_eq!(kind(batch.write_blob(&5u32.to_le_bytes())), Ok(()));
+        assert_eq!(kind(batch.write_blob(&4u32.to_le_bytes())), Err("out of order"));
+        assert_eq!(kind(batch.write_blob(&5u32.to_le_bytes())), Err("duplicate"));
+        assert_eq!(kind(batch.write_blob(&6u32.to_le_bytes())), Ok(()));
+        batch.commit()?;
+        assert_eq!(b.last_key(), Some(&6));
+
+        let keys: Vec<Option<u32>> = b.blobs().map(|(_, blob)| key(blob)).collect();
+        assert_eq!(keys, vec![Some(2), Some(2), None, Some(5), Some(6)]);
+
+        let err = io::Error::from(b.write_blob(&0u32.to_le_bytes()).unwrap_err());
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        Ok(())
+    }
+
+    #[test]
+    fn aborted_batches_dont_change_last_key() -> io::Result<()> {
+        let mut b = sorted_breccia()?;
+        b.write_blob(&2u32.to_le_bytes())?;
+
+        let mut batch = b.start_batch()?;
+        batch.write_blob(&10u32.to_le_bytes())?;
+        batch.abort()?;
+        assert_eq!(b.last_key(), Some(&2));
+
+        let mut batch = b.start_batch()?;
+        batch.write_blob(&10u32.to_le_bytes())?;
+        drop(batch);
+        assert_eq!(b.last_key(), Some(&2));
+
+        assert_eq!(kind(b.write_blob(&3u32.to_le_bytes())), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn check_sorted() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, ())?;
+        assert_eq!(b.check_sorted_by_key(key), Ok(()));
+        assert_eq!(b.sorted_runs_by_key(key), vec![]);
+
+        let mut offsets = vec![];
+        let mut batch = b.start_batch()?;
+        for blob in [&[][..], &1u32.to_le_bytes(), &3u32.to_le_bytes(), &3u32.to_le_bytes(), &[],
+                     &2u32.to_le_bytes(), &[0xff; 3], &1u32.to_le_bytes(), &4u32.to_le_bytes()]
+        {
+            offsets.push(batch.write_blob(blob)?);
+        }
+        batch.commit()?;
+
+        assert_eq!(b.check_sorted_by_key(key), Err(SortViolation { prev: offsets[3], next: offsets[5] }));
+
+        let runs = b.sorted_runs_by_key(key);
+        assert_eq!(runs, vec![Offset::ZERO .. offsets[5], offsets[5] .. offsets[7], offsets[7] .. b.committed().unwrap()]);
+
+        let run_keys: Vec<Vec<Option<u32>>> = runs.into_iter().map(|run| {
+            b.blobs_in(run).map(|(_, blob)| key(blob)).collect()
+        }).collect();
+        assert_eq!(run_keys, vec![vec![None, Some(1), Some(3), Some(3), None],
+                                  vec![Some(2), None],
+                                  vec![Some(1), Some(4)]]);
+
+        let mut b = sorted_breccia()?;
+        b.write_blob(&1u32.to_le_bytes())?;
+        b.write_blob(&1u32.to_le_bytes())?;
+        assert_eq!(b.check_sorted_by_key(key), Ok(()));
+        assert_eq!(b.sorted_runs_by_key(key), vec![Offset::ZERO .. b.committed().unwrap()]);
+        Ok(())
+    }
+
+    #[test]
+    fn last_key_recovered_on_open() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, ())?;
+        b.write_blob(&7u32.to_le_bytes())?;
+        b.write_blob(&[])?;
+
+        let mut b = SortedBrecciaMut::new(b, key as KeyFn);
+        assert_eq!(b.last_key(), Some(&7));
+        assert_eq!(kind(b.write_blob(&6u32.to_le_bytes())), Err("out of order"));
+
+        // An interrupted batch isn't used as the last key.
+        let mut batch = b.start_batch()?;
+        batch.write_blob(&100u32.to_le_bytes())?;
+        batch.batch.fd().flush()?;
+        std::mem::forget(batch);
+
+        let mut b = SortedBrecciaMut::new(b.into_inner(), key as KeyFn);
+        assert_eq!(b.last_key(), Some(&7));
+        assert!(b.start_batch().is_err());
+
+        assert!(!b.recover()?.is_clean());
+        assert_eq!(b.last_key(), Some(&7));
+        assert_eq!(kind(b.write_blob(&8u32.to_le_bytes())), Ok(()));
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+//! Fixtures shared by the tests of several modules.
+
+use std::io::{self, Write};
+
+use tempfile::tempfile;
+
+use super::{Breccia, BrecciaMut, Header, Marker, Offset};
+use super::header::HeaderExt;
+
+/// A word of blob data that isn't a marker.
+pub(crate) const DATA: Marker = Marker(0xfefe_fefe_fefe_fe2a);
+
+/// A header whose magic and serialized form together take more than one word.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BigHeader(pub(crate) u8);
+
+impl Header for BigHeader {
+    const MAGIC: &[u8] = b"\x00\x01\x02\x03\x04\x05\x06\x07\x08";
+    const SERIALIZED_SIZE: usize = 1;
+
+    fn serialize(&self, dst: &mut [u8]) {
+        dst[0] = self.0;
+    }
+
+    type DeserializeError = std::convert::Infallible;
+    fn deserialize(src: &[u8]) -> Result<Self, Self::DeserializeError> {
+        Ok(Self(src[0]))
+    }
+}
+
+/// Creates a breccia directly from marker words, to test corrupt or partially written files.
+pub(crate) fn breccia_from_markers<H: Header>(header: H, markers: &[Marker]) -> io::Result<Breccia<H>> {
+    let mut fd = tempfile()?;
+    fd.write_all(H::MAGIC)?;
+
+    let mut header_bytes = vec![0u8; H::SERIALIZED_SIZE];
+    header.serialize(&mut header_bytes);
+    fd.write_all(&header_bytes)?;
+    fd.write_all(&[0; size_of::<Marker>()][0 .. H::PADDING_SIZE])?;
+
+    for marker in markers {
+        fd.write_all(&marker.to_bytes())?;
+    }
+    Ok(Breccia::open_file(fd)?)
+}
+
+/// Keys blobs by their first four bytes, read as a little-endian `u32`.
+pub(crate) fn key(blob: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(blob.get(0 .. 4)?.try_into().ok()?))
+}
+
+/// Writes one blob per key, padded out with `padding` extra bytes, in a single batch.
+pub(crate) fn write_keys(b: &mut BrecciaMut, keys: impl IntoIterator<Item = u32>, padding: usize) -> io::Result<()> {
+    let mut batch = b.start_batch()?;
+    for k in keys {
+        let mut blob = k.to_le_bytes().to_vec();
+        blob.resize(4 + padding, 0);
+        batch.write_blob(&blob)?;
+    }
+    batch.commit()
+}
+
+/// Creates a breccia with one blob per key, padded out with `padding` extra bytes.
+pub(crate) fn breccia_of_keys(keys: &[u32], padding: usize) -> io::Result<BrecciaMut> {
+    let mut b = BrecciaMut::create_from_file(tempfile()?, ())?;
+    write_keys(&mut b, keys.iter().copied(), padding)?;
+    Ok(b)
+}
+
+/// Checks the result of searching `b` for `target` by `key`. A hit must be a blob of `b` with
+/// that key, and a miss must be at the lower bound of `target`, with no blob having that key.
+pub(crate) fn assert_search_result<H: Header>(b: &Breccia<H>,
+                                              target: u32,
+                                              result: Result<(Offset<H>, &[u8]), Offset<H>>)
+{
+    match result {
+        Ok((offset, blob)) => {
+            assert_eq!(key(blob), Some(target));
+            assert_eq!(b.get_blob(offset).unwrap().as_bytes(), blob);
+        },
+        Err(offset) => {
+            assert_eq!(offset, b.lower_bound(&target, key));
+            assert_ne!(b.blobs_from(offset).find_map(|(_, blob)| key(blob)), Some(target));
+        },
+    }
+}
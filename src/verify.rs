@@ -0,0 +1,223 @@
+use std::ops::Range;
+
+use super::{Breccia, Header, Marker, Offset, Tail, padding_needed};
+use super::marker::State::Dirty;
+
+/// A structural problem found by `Breccia::verify`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// The kind of problem.
+    pub kind: ProblemKind,
+
+    /// The range of bytes within the file that the problem was found in.
+    pub range: Range<u64>,
+}
+
+/// The kinds of problems that `Breccia::verify` can find.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProblemKind {
+    /// The file doesn't start with the initial marker, so the words before the first marker can't
+    /// be part of any blob.
+    MissingInitialMarker,
+
+    /// The initial marker is dirty.
+    ///
+    /// The initial marker is written clean when the file is created. Other dirty markers before
+    /// the last clean marker are normal: every end marker in a batch other than the last is dirty.
+    DirtyInitialMarker,
+
+    /// A padding marker that is not followed by blob data.
+    MisplacedPadding,
+
+    /// An end marker with a padding length longer than its blob.
+    BadPaddingLength,
+
+    /// The padding bytes at the end of a blob are not all `0xfe`.
+    BadTailPadding,
+
+    /// A blob is preceded by a different number of padding markers than is needed to avoid
+    /// collisions.
+    NonCanonicalPadding,
+
+    /// Data after the last committed end marker, left behind by a batch that was never committed.
+    Uncommitted,
+}
+
+/// The result of `Breccia::verify`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The number of complete blobs checked, including uncommitted blobs.
+    pub blobs: usize,
+
+    /// The problems found, in file order.
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    /// Returns `true` if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn push(&mut self, kind: ProblemKind, range: Range<u64>) {
+        self.problems.push(Problem { kind, range });
+    }
+}
+
+impl<H: Header> Breccia<H> {
+    /// Verifies the structure of the entire file.
+    ///
+    /// Every word is checked, so this takes time proportional to the size of the file.
+    ///
+    /// Markers out of sequence, such as a marker copied to the wrong place, are not reported. A
+    /// word is only a marker if its offset matches its own position, and blob data may contain
+    /// any word whose offset doesn't, so such a word is indistinguishable from blob data.
+    ///
+    /// Of the dirty markers before the last clean marker, only a dirty initial marker is reported.
+    /// Every end marker in a batch other than the last is dirty, and nothing else marks where a
+    /// batch starts, so the dirty markers of an interrupted batch that were followed by another
+    /// batch's blobs look just like those of one longer batch.
+    pub fn verify(&self) -> VerifyReport {
+        let map = self.map();
+        let mut report = VerifyReport::default();
+
+        let is_marker = |i: usize| map[i].offset::<H>().raw == i;
+        let word_range = |start: usize, end: usize| {
+            Offset::<H>::new(start).to_file_offset() .. Offset::<H>::new(end).to_file_offset()
+        };
+
+        let Some(mut start) = (0 .. map.len()).find(|i| is_marker(*i)) else {
+            report.push(ProblemKind::MissingInitialMarker, word_range(0, map.len()));
+            return report;
+        };
+
+        if start != 0 {
+            report.push(ProblemKind::MissingInitialMarker, word_range(0, start));
+        } else if map[0].state() == Dirty {
+            report.push(ProblemKind::DirtyInitialMarker, word_range(0, 1));
+        }
+
+        loop {
+            // Padding markers can only directly follow the start marker, or other padding markers.
+            let mut data_start = start + 1;
+            while data_start < map.len() && is_marker(data_start) && map[data_start].is_padding() {
+                data_start += 1;
+            }
+            let padding = data_start - (start + 1);
+
+            let Some(end) = (data_start .. map.len()).find(|i| is_marker(*i)) else {
+                // Partially written blob; covered by the tail check below.
+                break
+            };
+
+            if padding > 0 && end == data_start {
+                report.push(ProblemKind::MisplacedPadding, word_range(start + 1, data_start));
+                start = end;
+                continue
+            }
+
+            let data = Marker::slice_to_bytes(&map[data_start .. end]);
+            if let Some(blob_len) = data.len().checked_sub(map[end].padding_len()) {
+                report.blobs += 1;
+
+                let (blob, tail_padding) = data.split_at(blob_len);
+                if tail_padding.iter().any(|b| *b != 0xfe) {
+                    let end_offset = Offset::<H>::new(end).to_file_offset();
+                    report.push(ProblemKind::BadTailPadding,
+                                end_offset - tail_padding.len() as u64 .. end_offset);
+                }
+
+                if padding_needed(Offset::<H>::new(start), blob) != padding {
+                    report.push(ProblemKind::NonCanonicalPadding, word_range(start + 1, data_start));
+                }
+            } else {
+                report.push(ProblemKind::BadPaddingLength, word_range(end, end + 1));
+            }
+
+            start = end;
+        }
+
+        if self.tail != Tail::Clean {
+            report.push(ProblemKind::Uncommitted, self.committed_len() .. self.map.len() as u64);
+        }
+
+        report.problems.sort_by_key(|problem| problem.range.start);
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+
+    use tempfile::tempfile;
+
+    use crate::BrecciaMut;
+    use crate::marker::State::Clean;
+    use crate::test_util::{self, DATA};
+
+    fn breccia_from_markers(markers: &[Marker]) -> io::Result<Breccia> {
+        test_util::breccia_from_markers((), markers)
+    }
+
+    fn kinds(report: &VerifyReport) -> Vec<ProblemKind> {
+        report.problems.iter().map(|problem| problem.kind).collect()
+    }
+
+    #[test]
+    fn verify_clean() -> io::Result<()> {
+        let mut b = BrecciaMut::create_from_file(tempfile()?, ())?;
+        assert_eq!(b.verify(), VerifyReport::default());
+
+        let mut batch = b.start_batch()?;
+        batch.write_blob(&[])?;
+        batch.write_blob(&[42])?;
+        batch.write_blob(&[2,0,0,0,0,0,0,0b1110_0000])?;
+        batch.write_blob(&[0; 100])?;
+        batch.commit()?;
+
+        let report = b.verify();
+        assert!(report.is_ok());
+        assert_eq!(report.blobs, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn verify_problems() -> io::Result<()> {
+        let b = breccia_from_markers(&[DATA, Marker(1)])?;
+        assert_eq!(b.verify().problems,
+                   vec![Problem { kind: ProblemKind::MissingInitialMarker, range: 8 .. 16 }]);
+
+        let b = breccia_from_markers(&[Marker::new(Offset::<()>::new(0), 0, Dirty)])?;
+        assert_eq!(kinds(&b.verify()), vec![ProblemKind::DirtyInitialMarker, ProblemKind::Uncommitted]);
+
+        let b = breccia_from_markers(&[Marker(0), Marker::new_padding(Offset::<()>::new(1)), Marker(2)])?;
+        assert_eq!(b.verify().problems,
+                   vec![Problem { kind: ProblemKind::MisplacedPadding, range: 16 .. 24 }]);
+
+        let b = breccia_from_markers(&[Marker(0), Marker::new(Offset::<()>::new(1), 3, Clean)])?;
+        assert_eq!(b.verify().problems,
+                   vec![Problem { kind: ProblemKind::BadPaddingLength, range: 16 .. 24 }]);
+
+        let b = breccia_from_markers(&[Marker(0), Marker(0x2a), Marker::new(Offset::<()>::new(2), 3, Clean)])?;
+        let report = b.verify();
+        assert_eq!(report.blobs, 1);
+        assert_eq!(report.problems,
+                   vec![Problem { kind: ProblemKind::BadTailPadding, range: 21 .. 24 }]);
+
+        let b = breccia_from_markers(&[Marker(0), Marker::new_padding(Offset::<()>::new(1)), DATA,
+                                       Marker::new(Offset::<()>::new(3), 7, Clean)])?;
+        assert_eq!(b.verify().problems,
+                   vec![Problem { kind: ProblemKind::NonCanonicalPadding, range: 16 .. 24 }]);
+
+        let b = breccia_from_markers(&[Marker(0), DATA, Marker::new(Offset::<()>::new(2), 7, Dirty), DATA])?;
+        let report = b.verify();
+        assert_eq!(report.blobs, 1);
+        assert_eq!(report.problems,
+                   vec![Problem { kind: ProblemKind::Uncommitted, range: 16 .. 40 }]);
+
+        Ok(())
+    }
+}